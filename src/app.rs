@@ -1,12 +1,203 @@
-use super::chat::{message::Message, Recepients, UdpChat};
-use directories::ProjectDirs;
+use super::config::Config;
+use chrono::{Duration, Local, NaiveDate, TimeZone};
 use eframe::{egui, epi};
+use egui::text::{LayoutJob, TextFormat};
 use egui::*;
-use epi::Storage;
+use epi::{RepaintSignal, Storage};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use udp_chat::chat::{
+    crypto::fingerprint,
+    message::{clean_name, Message, DEFAULT_CHANNEL},
+    network::{interfaces, Discovery, Interface, NetInterface},
+    peers::{NodeId, Presence},
+    store::{Hit, Query},
+    transfer::{is_image, Transfer, TransferState},
+    Recepients,
+};
+use udp_chat::{ChatEvent, UdpChat};
+
+/// Largest side of an image preview in points.
+const PREVIEW: u32 = 240;
+/// Background of search matches and of the message jumped to.
+const FOUND: Color32 = Color32::from_rgb(110, 90, 20);
 
 pub struct ChatApp {
     chat: UdpChat,
     text: String,
+    config: Config,
+    first_run: bool,
+    settings: Option<Settings>,
+    show_keys: bool,
+    /// Escape asked to clear the history, waiting for a yes.
+    confirm_clear: bool,
+    search: Option<Search>,
+    /// Message picked from the search results, by sender and id.
+    found: Option<(NodeId, u64)>,
+    /// The history has yet to scroll to `found`.
+    scroll_to_found: bool,
+    /// Heights of the history rows laid out so far, by sender and id,
+    /// so those scrolled out of sight only take up their space.
+    row_heights: HashMap<(NodeId, u64), f32>,
+    /// Width the rows were laid out at.
+    rows_width: f32,
+    /// Row to keep at the top while older ones are loaded above it.
+    anchor: Option<(NodeId, u64)>,
+    /// Peer of the open direct conversation, `None` for the public room.
+    conversation: Option<NodeId>,
+    /// Room shown when no direct conversation is open.
+    channel: String,
+    new_channel: String,
+    /// Path typed into the file picker, `None` while it is closed.
+    attach: Option<String>,
+    status: String,
+    /// Thumbnails by transfer id, `None` while decoding or if that failed.
+    previews: HashMap<u64, Option<(TextureId, Vec2)>>,
+    /// Decoded thumbnails by transfer id, waiting to become textures.
+    thumbnails: Receiver<(u64, Thumbnail)>,
+    decoded: Sender<(u64, Thumbnail)>,
+    events: Option<Receiver<ChatEvent>>,
+    repaint_signal: Option<Arc<dyn RepaintSignal>>,
+}
+
+/// Editable copy of the config shown in the settings window.
+struct Settings {
+    name: String,
+    port: u16,
+    interface: Interface,
+    /// What there was to choose from when the window opened.
+    interfaces: Vec<NetInterface>,
+    discovery: Discovery,
+    db_path: String,
+    /// Custom status shown to peers.
+    away_text: String,
+    status: String,
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> Self {
+        Settings {
+            name: config.name.to_owned(),
+            port: config.port,
+            interface: config.interface.to_owned(),
+            interfaces: interfaces(),
+            discovery: config.discovery,
+            db_path: config
+                .db_path
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            away_text: config.status.to_owned(),
+            status: String::new(),
+        }
+    }
+    fn to_config(&self) -> Result<Config, String> {
+        let name = clean_name(&self.name);
+        if name.is_empty() {
+            return Err("Nickname is empty.".to_string());
+        }
+        let db_path = match self.db_path.trim() {
+            "" => None,
+            path => Some(PathBuf::from(path)),
+        };
+        Ok(Config {
+            name,
+            port: self.port,
+            interface: self.interface.to_owned(),
+            discovery: self.discovery,
+            db_path,
+            channels: Vec::new(),
+            presence: Presence::default(),
+            status: self.away_text.trim().to_string(),
+            ..Config::default()
+        })
+    }
+}
+
+/// The search window: what to look for and what was found.
+struct Search {
+    text: String,
+    sender: Option<NodeId>,
+    channel: Option<String>,
+    /// Dates as typed, YYYY-MM-DD, empty for no limit.
+    from: String,
+    to: String,
+    /// What there was to filter by when the window opened.
+    senders: Vec<(NodeId, String)>,
+    channels: Vec<String>,
+    hits: Vec<Hit>,
+    status: String,
+}
+
+impl Search {
+    fn new(chat: &UdpChat) -> Self {
+        Search {
+            text: String::new(),
+            sender: None,
+            channel: None,
+            from: String::new(),
+            to: String::new(),
+            senders: chat.search_senders(),
+            channels: chat.search_channels(),
+            hits: Vec::new(),
+            status: String::new(),
+        }
+    }
+    fn to_query(&self) -> Result<Query, String> {
+        // Midnight starting the day, or the day after for `next`.
+        let day = |text: &str, next: bool| match text.trim() {
+            "" => Ok(None),
+            text => NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| if next { date.succ_opt() } else { Some(date) })
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|midnight| {
+                    // Where clocks skip midnight, the day starts an hour later.
+                    Local
+                        .from_local_datetime(&midnight)
+                        .earliest()
+                        .or_else(|| {
+                            Local
+                                .from_local_datetime(&(midnight + Duration::hours(1)))
+                                .earliest()
+                        })
+                        .map(|time| time.timestamp().max(0) as u64)
+                })
+                .ok_or_else(|| format!("{} is not a YYYY-MM-DD date.", text)),
+        };
+        Ok(Query {
+            text: self.text.trim().to_string(),
+            sender: self.sender,
+            channel: self.channel.to_owned(),
+            since: day(&self.from, false)?,
+            // The whole last day.
+            until: day(&self.to, true)?,
+        })
+    }
+    fn run(&mut self, chat: &UdpChat) {
+        let query = match self.to_query() {
+            Ok(query) => query,
+            Err(err) => {
+                self.status = err;
+                return;
+            }
+        };
+        match chat.search(&query) {
+            Ok(hits) => {
+                self.status = match hits.len() {
+                    0 => "Nothing found.".to_string(),
+                    1 => "1 message".to_string(),
+                    n => format!("{} messages", n),
+                };
+                self.hits = hits;
+            }
+            Err(err) => self.status = format!("Search failed: {}", err),
+        }
+    }
 }
 
 impl epi::App for ChatApp {
@@ -28,89 +219,808 @@ impl epi::App for ChatApp {
         frame: &mut epi::Frame<'_>,
         _storage: Option<&dyn Storage>,
     ) {
-        self.chat.prelude(frame.repaint_signal());
+        self.repaint_signal = Some(frame.repaint_signal());
+        if !self.first_run {
+            self.start();
+        }
     }
     fn on_exit(&mut self) {
-        self.chat.message = Message::exit();
-        self.chat.send(Recepients::All);
+        self.chat.shutdown();
     }
 
-    fn update(&mut self, ctx: &egui::CtxRef, _frame: &mut epi::Frame<'_>) {
+    fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
         self.chat.receive();
+        self.handle_events();
+        if !ctx.input().raw.events.is_empty() {
+            self.chat.active();
+        }
+        let dropped: Vec<PathBuf> = ctx
+            .input()
+            .raw
+            .dropped_files
+            .iter()
+            .filter_map(|file| file.path.clone())
+            .collect();
+        dropped.iter().for_each(|path| self.send_file(path));
+        self.load_previews(frame);
         self.draw(ctx);
-        self.handle_keys(ctx);
+        if self.show_keys {
+            self.draw_keys(ctx);
+        }
+        if self.search.is_some() {
+            self.draw_search(ctx);
+        }
+        if self.confirm_clear {
+            self.draw_confirm_clear(ctx);
+        }
+        if self.settings.is_some() {
+            self.draw_settings(ctx);
+        } else {
+            self.handle_keys(ctx);
+        }
         // ctx.request_repaint();
     }
 }
 
-impl Default for ChatApp {
-    fn default() -> Self {
-        let db_path = ProjectDirs::from("com", "p4ymak", env!("CARGO_PKG_NAME")).map(|p| {
-            std::fs::create_dir_all(&p.data_dir()).ok();
-            p.data_dir().join("history.db")
-        });
-        ChatApp {
-            chat: UdpChat::new("XXX".to_string(), 4444, db_path),
+impl ChatApp {
+    pub fn new(config: Config, first_run: bool) -> io::Result<Self> {
+        let (decoded, thumbnails) = mpsc::channel();
+        Ok(ChatApp {
+            chat: config.chat()?,
             text: String::new(),
+            settings: first_run.then(|| Settings::from_config(&config)),
+            config,
+            first_run,
+            show_keys: false,
+            confirm_clear: false,
+            search: None,
+            found: None,
+            scroll_to_found: false,
+            row_heights: HashMap::new(),
+            rows_width: 0.0,
+            anchor: None,
+            conversation: None,
+            channel: DEFAULT_CHANNEL.to_string(),
+            new_channel: String::new(),
+            attach: None,
+            status: String::new(),
+            previews: HashMap::new(),
+            thumbnails,
+            decoded,
+            events: None,
+            repaint_signal: None,
+        })
+    }
+    fn join_channel(&mut self) {
+        if let Some(channel) = self.chat.join(&self.new_channel) {
+            self.channel = channel;
+            self.conversation = None;
+            self.new_channel = String::new();
+            self.save_channels();
+        }
+    }
+    fn leave_channel(&mut self, channel: &str) {
+        self.chat.leave(channel);
+        if self.channel == channel {
+            self.channel = DEFAULT_CHANNEL.to_string();
+        }
+        self.save_channels();
+    }
+    /// Offers a file to the open conversation.
+    fn send_file(&mut self, path: &Path) {
+        let addrs = match self.conversation {
+            Some(node) => Recepients::One(node),
+            None => Recepients::Peers,
+        };
+        self.status = match self.chat.send_file(path, addrs, &self.channel) {
+            Ok(_) => String::new(),
+            Err(err) => format!("{}: {}", path.display(), err),
+        };
+    }
+    fn send_attachment(&mut self) {
+        if let Some(path) = self.attach.take() {
+            self.send_file(Path::new(path.trim()));
+            if !self.status.is_empty() {
+                self.attach = Some(path);
+            }
+        }
+    }
+    /// Decodes thumbnails of new images in the background and turns
+    /// the decoded ones into textures.
+    fn load_previews(&mut self, frame: &mut epi::Frame<'_>) {
+        while let Ok((id, thumbnail)) = self.thumbnails.try_recv() {
+            let preview = thumbnail.map(|(size, pixels)| {
+                (
+                    frame
+                        .tex_allocator()
+                        .alloc_srgba_premultiplied(size, &pixels),
+                    Vec2::new(size.0 as f32, size.1 as f32),
+                )
+            });
+            self.previews.insert(id, preview);
+        }
+        for id in self.chat.history().iter().filter_map(|m| m.file) {
+            if self.previews.contains_key(&id) {
+                continue;
+            }
+            if let Some(t) = self.chat.transfers().get(&id) {
+                if (t.state == TransferState::Done || !t.incoming) && is_image(&t.path) {
+                    self.previews.insert(id, None);
+                    let path = t.path.clone();
+                    let decoded = self.decoded.clone();
+                    self.chat.blocking(move || {
+                        decoded.send((id, thumbnail(&path))).ok();
+                    });
+                }
+            }
+        }
+    }
+    /// Name with the presence icon, for tabs.
+    fn peer_label(&self, node: &NodeId) -> String {
+        match self.chat.peers().get(node) {
+            Some(peer) => format!("{} {}", peer.presence.icon(), self.chat.peer_name(node)),
+            None => self.chat.peer_name(node),
+        }
+    }
+    fn peer_hover(&self, node: &NodeId) -> String {
+        match self.chat.peers().get(node) {
+            Some(peer) if !peer.status.is_empty() => {
+                format!("{} ({}): {}", peer.addr, peer.presence, peer.status)
+            }
+            Some(peer) => format!("{} ({})", peer.addr, peer.presence),
+            None => node.to_string(),
+        }
+    }
+    fn set_presence(&mut self, presence: Presence) {
+        self.chat.set_presence(presence, &self.config.status);
+        self.config.presence = presence;
+        if let Err(err) = self.config.save() {
+            self.status = format!("Status not saved: {}", err);
+        }
+    }
+    fn save_channels(&mut self) {
+        self.config.channels = self.chat.channels().to_owned();
+        if let Err(err) = self.config.save() {
+            self.status = format!("Channels not saved: {}", err);
+        }
+    }
+    fn start(&mut self) {
+        self.events = Some(self.chat.subscribe());
+        if let Some(repaint_signal) = &self.repaint_signal {
+            let repaint_signal = Arc::clone(repaint_signal);
+            self.chat
+                .prelude(Arc::new(move || repaint_signal.request_repaint()));
+        }
+    }
+    /// Tells about what the history alone doesn't show.
+    fn handle_events(&mut self) {
+        while let Some(event) = self.events.as_ref().and_then(|rx| rx.try_recv().ok()) {
+            match event {
+                ChatEvent::KeyRefused { node } => {
+                    self.status = format!(
+                        "⚠ Someone posed as {} with another key!",
+                        self.chat.peer_name(&node)
+                    );
+                }
+                ChatEvent::Transfer {
+                    id,
+                    state: TransferState::Failed,
+                } => {
+                    if let Some(t) = self.chat.transfers().get(&id) {
+                        self.status = format!("{} failed.", t.name);
+                    }
+                }
+                ChatEvent::OfferFailed { path, error } => {
+                    self.status = format!("{}: {}", path.display(), error);
+                }
+                _ => (),
+            }
+        }
+    }
+    fn apply_settings(&mut self) {
+        if let Some(settings) = &mut self.settings {
+            match settings.to_config() {
+                Ok(config) => {
+                    let config = Config {
+                        channels: self.config.channels.to_owned(),
+                        presence: self.config.presence,
+                        profile: self.config.profile.to_owned(),
+                        overrides: self.config.overrides.to_owned(),
+                        ..config
+                    };
+                    if let Err(err) = config.save() {
+                        settings.status = format!("Not saved: {}", err);
+                        return;
+                    }
+                    if self.first_run {
+                        self.chat = match config.chat() {
+                            Ok(chat) => chat,
+                            Err(err) => {
+                                settings.status = format!("Not started: {}", err);
+                                return;
+                            }
+                        };
+                        self.config = config;
+                        self.first_run = false;
+                        self.settings = None;
+                        self.start();
+                    } else if config != self.config {
+                        // Status and interface change right away, the rest needs a restart.
+                        if config.status != self.config.status {
+                            self.chat.set_presence(self.chat.presence(), &config.status);
+                        }
+                        if config.interface != self.config.interface {
+                            self.chat.set_interface(config.interface.to_owned());
+                        }
+                        let restart = Config {
+                            status: self.config.status.to_owned(),
+                            interface: self.config.interface.to_owned(),
+                            ..config.clone()
+                        } != self.config;
+                        self.config = config;
+                        if restart {
+                            settings.status = "Saved. Restart to apply.".to_string();
+                        } else {
+                            self.settings = None;
+                        }
+                    } else {
+                        self.settings = None;
+                    }
+                }
+                Err(err) => settings.status = err,
+            }
+        }
+    }
+    fn draw_keys(&mut self, ctx: &egui::CtxRef) {
+        let chat = &mut self.chat;
+        egui::Window::new("Keys")
+            .open(&mut self.show_keys)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Compare fingerprints in person before marking them verified.");
+                ui.separator();
+                ui.label(format!(
+                    "You: {}",
+                    fingerprint(chat.keyring().public.as_bytes())
+                ));
+                let mut peers: Vec<(String, NodeId, String)> = chat
+                    .peers()
+                    .iter()
+                    .filter(|(node, _)| *node != &chat.node())
+                    .map(|(node, peer)| (peer.name.to_owned(), *node, peer.addr.to_string()))
+                    .collect();
+                peers.sort();
+                egui::Grid::new("keys_grid").show(ui, |ui| {
+                    for (name, node, addr) in peers {
+                        ui.label(name).on_hover_text(addr);
+                        match chat.keyring().peer_key(&node) {
+                            Some(key) => {
+                                ui.monospace(fingerprint(&key));
+                                let mut trusted = chat.keyring().is_trusted(&key);
+                                if ui.checkbox(&mut trusted, "verified").changed() {
+                                    chat.set_trusted(key, trusted);
+                                }
+                            }
+                            None => {
+                                ui.label("no key yet");
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+    }
+    fn draw_confirm_clear(&mut self, ctx: &egui::CtxRef) {
+        let mut clear = false;
+        let mut close = false;
+        egui::Window::new("Clear history")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Delete every message on this computer?");
+                ui.horizontal(|ui| {
+                    clear = ui.button("Clear").clicked();
+                    close = ui.button("Cancel").clicked();
+                });
+            });
+        if clear {
+            self.chat.clear_history();
+        }
+        if clear || close {
+            self.confirm_clear = false;
+        }
+    }
+    fn draw_search(&mut self, ctx: &egui::CtxRef) {
+        let mut open = true;
+        let mut picked = None;
+        if let Some(search) = &mut self.search {
+            let chat = &self.chat;
+            egui::Window::new("Search")
+                .open(&mut open)
+                .default_width(420.0)
+                .show(ctx, |ui| {
+                    let mut find = false;
+                    ui.horizontal(|ui| {
+                        let words = ui.add(
+                            egui::TextEdit::singleline(&mut search.text)
+                                .hint_text("words")
+                                .id(Id::new("search_input")),
+                        );
+                        find = ui.button("Find").clicked()
+                            || (words.lost_focus() && ui.input().key_pressed(Key::Enter));
+                    });
+                    egui::Grid::new("search_grid").show(ui, |ui| {
+                        ui.label("From");
+                        let sender_name = search
+                            .senders
+                            .iter()
+                            .find(|(node, _)| Some(*node) == search.sender)
+                            .map(|(_, name)| name.to_owned())
+                            .unwrap_or_else(|| "anyone".to_string());
+                        egui::ComboBox::from_id_source("search_sender")
+                            .selected_text(sender_name)
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut search.sender, None, "anyone");
+                                for (node, name) in &search.senders {
+                                    ui.selectable_value(&mut search.sender, Some(*node), name)
+                                        .on_hover_text(node.to_string());
+                                }
+                            });
+                        ui.end_row();
+                        ui.label("In");
+                        egui::ComboBox::from_id_source("search_channel")
+                            .selected_text(match &search.channel {
+                                Some(channel) => format!("#{}", channel),
+                                None => "everywhere".to_string(),
+                            })
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut search.channel, None, "everywhere");
+                                for channel in &search.channels {
+                                    ui.selectable_value(
+                                        &mut search.channel,
+                                        Some(channel.to_owned()),
+                                        format!("#{}", channel),
+                                    );
+                                }
+                            });
+                        ui.end_row();
+                        ui.label("Sent");
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::TextEdit::singleline(&mut search.from)
+                                    .desired_width(90.0)
+                                    .hint_text("YYYY-MM-DD"),
+                            );
+                            ui.label("to");
+                            ui.add(
+                                egui::TextEdit::singleline(&mut search.to)
+                                    .desired_width(90.0)
+                                    .hint_text("YYYY-MM-DD"),
+                            );
+                        });
+                        ui.end_row();
+                    });
+                    if find {
+                        search.run(chat);
+                    }
+                    ui.label(&search.status);
+                    egui::ScrollArea::vertical()
+                        .max_height(320.0)
+                        .show(ui, |ui| {
+                            for hit in &search.hits {
+                                let m = &hit.entry;
+                                let place = match m.direct {
+                                    Some(peer) => format!("→ {}", chat.peer_name(&peer)),
+                                    None => format!("#{}", m.channel),
+                                };
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::Label::new(sent_at(m.timestamp)).small().weak());
+                                    ui.add(egui::Label::new(&m.name).strong());
+                                    ui.add(egui::Label::new(place).weak());
+                                });
+                                if highlighted(ui, &m.text, &hit.matches)
+                                    .on_hover_text("Show in the chat")
+                                    .clicked()
+                                {
+                                    picked = Some(m.to_owned());
+                                }
+                                ui.separator();
+                            }
+                        });
+                });
+        }
+        if !open {
+            self.search = None;
+        }
+        if let Some(m) = picked {
+            self.chat.load_back_to(&m);
+            match m.direct {
+                Some(peer) => self.conversation = Some(peer),
+                None => {
+                    self.conversation = None;
+                    self.channel = m.channel;
+                }
+            }
+            self.found = Some((m.node, m.id));
+            self.scroll_to_found = true;
+        }
+    }
+    fn draw_settings(&mut self, ctx: &egui::CtxRef) {
+        let mut apply = false;
+        let mut close = false;
+        if let Some(settings) = &mut self.settings {
+            egui::Window::new("Settings")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    egui::Grid::new("settings_grid").show(ui, |ui| {
+                        ui.label("Nickname");
+                        ui.add(egui::TextEdit::singleline(&mut settings.name));
+                        ui.end_row();
+                        ui.label("Port");
+                        ui.add(egui::DragValue::new(&mut settings.port).clamp_range(1..=65535));
+                        ui.end_row();
+                        ui.label("Interface");
+                        egui::ComboBox::from_id_source("interface")
+                            .selected_text(settings.interface.to_string())
+                            .show_ui(ui, |ui| {
+                                let mut choices = vec![Interface::Auto, Interface::All];
+                                if let Interface::Ip(_) = settings.interface {
+                                    choices.push(settings.interface.to_owned());
+                                }
+                                for iface in &settings.interfaces {
+                                    let choice = Interface::Name(iface.name.to_owned());
+                                    if !choices.contains(&choice) {
+                                        choices.push(choice);
+                                    }
+                                }
+                                for choice in choices {
+                                    let addresses = settings
+                                        .interfaces
+                                        .iter()
+                                        .filter(|iface| {
+                                            Interface::Name(iface.name.to_owned()) == choice
+                                        })
+                                        .map(|iface| iface.ip.to_string())
+                                        .collect::<Vec<String>>();
+                                    let label = match addresses.is_empty() {
+                                        true => choice.to_string(),
+                                        false => format!("{} {}", choice, addresses.join(", ")),
+                                    };
+                                    ui.selectable_value(&mut settings.interface, choice, label);
+                                }
+                            });
+                        ui.end_row();
+                        ui.label("Discovery");
+                        ui.horizontal(|ui| {
+                            for mode in [Discovery::Broadcast, Discovery::Multicast] {
+                                ui.radio_value(&mut settings.discovery, mode, mode.to_string());
+                            }
+                        });
+                        ui.end_row();
+                        ui.label("History");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut settings.db_path).hint_text("default"),
+                        );
+                        ui.end_row();
+                        ui.label("Status");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut settings.away_text)
+                                .hint_text("what you are up to"),
+                        );
+                        ui.end_row();
+                    });
+                    ui.label(&settings.status);
+                    ui.horizontal(|ui| {
+                        apply = ui.button("Save").clicked();
+                        if !self.first_run {
+                            close = ui.button("Cancel").clicked();
+                        }
+                    });
+                });
+        }
+        if apply {
+            self.apply_settings();
+        } else if close {
+            self.settings = None;
         }
     }
-}
-impl ChatApp {
     fn handle_keys(&mut self, ctx: &egui::CtxRef) {
+        let joining = ctx.memory().has_focus(Id::new("channel_input"));
+        let attaching = ctx.memory().has_focus(Id::new("attach_input"));
+        let writing = ctx.memory().has_focus(Id::new("text_input"));
+        // The message box keeps the focus, so it only counts once written in.
+        // Escape drops the focus of a field before we get here, so what was
+        // typed counts too.
+        let editing = ctx
+            .memory()
+            .focus()
+            .is_some_and(|id| id != Id::new("text_input"))
+            || !self.text.is_empty()
+            || !self.new_channel.is_empty();
+        let windows = self.show_keys || self.search.is_some() || self.attach.is_some();
         for event in &ctx.input().raw.events {
             match event {
                 Event::Key {
                     key: egui::Key::Enter,
                     pressed: true,
                     ..
-                } => self.send(),
+                } if joining => self.join_channel(),
+                Event::Key {
+                    key: egui::Key::Enter,
+                    pressed: true,
+                    ..
+                } if attaching => self.send_attachment(),
+                Event::Key {
+                    key: egui::Key::Enter,
+                    pressed: true,
+                    modifiers,
+                } if !modifiers.shift && writing => self.send(),
                 Event::Key {
                     key: egui::Key::Escape,
                     pressed: true,
                     ..
-                } => self.chat.clear_history(),
-
+                } if !editing && !windows => self.confirm_clear = true,
                 _ => (),
             }
         }
     }
     fn send(&mut self) {
         if !self.text.trim().is_empty() {
-            self.chat.message = Message::text(&self.text);
-            self.chat.send(Recepients::Peers);
+            match self.conversation {
+                Some(node) => {
+                    self.chat
+                        .send(Message::direct(&self.text), Recepients::One(node));
+                }
+                None => {
+                    self.chat.send(
+                        Message::text(&self.text).in_channel(&self.channel),
+                        Recepients::Peers,
+                    );
+                }
+            }
         }
         self.text = String::new();
     }
     fn draw(&mut self, ctx: &egui::CtxRef) {
         egui::TopBottomPanel::top("socket").show(ctx, |ui| {
             ui.with_layout(egui::Layout::left_to_right(), |ui| {
+                let roster = self
+                    .chat
+                    .peers()
+                    .iter()
+                    .map(|(_, peer)| {
+                        format!(
+                            "{} {} ({}) {} {}",
+                            peer.presence.icon(),
+                            peer.name,
+                            peer.addr,
+                            peer.last_seen.format("%H:%M"),
+                            peer.status
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
                 ui.add(
-                    egui::Label::new(format!("Online: {}", self.chat.peers.len()))
+                    egui::Label::new(format!("Online: {}", self.chat.peers().len()))
                         .wrap(false)
                         .strong(),
+                )
+                .on_hover_text(roster);
+                let mut presence = self.chat.presence();
+                egui::ComboBox::from_id_source("presence")
+                    .selected_text(presence.icon())
+                    .width(40.0)
+                    .show_ui(ui, |ui| {
+                        for p in [Presence::Online, Presence::Away, Presence::Busy] {
+                            ui.selectable_value(&mut presence, p, format!("{} {}", p.icon(), p));
+                        }
+                    });
+                if presence != self.chat.presence() {
+                    self.set_presence(presence);
+                }
+                let connection = self.chat.connection();
+                match connection.is_online() {
+                    true => ui.label(connection.to_string()),
+                    false => ui.add(
+                        egui::Label::new(connection.to_string()).text_color(Color32::LIGHT_RED),
+                    ),
+                }
+                .on_hover_text(format!("Inbox: {}", self.chat.inbox_stats()));
+                if !connection.is_online()
+                    && ui
+                        .small_button("⟳")
+                        .on_hover_text("Try to connect again")
+                        .clicked()
+                {
+                    self.chat.reconnect();
+                }
+                ui.label(self.chat.db_status());
+                ui.label(&self.status);
+                if ui.small_button("⚙").clicked() && self.settings.is_none() {
+                    self.settings = Some(Settings::from_config(&self.config));
+                }
+                if ui.small_button("🔑").clicked() {
+                    self.show_keys = !self.show_keys;
+                }
+                if ui.small_button("🔍").on_hover_text("Search").clicked() {
+                    self.search = match self.search {
+                        Some(_) => None,
+                        None => Some(Search::new(&self.chat)),
+                    };
+                }
+            });
+        });
+        let mut tabs: Vec<NodeId> = Vec::new();
+        for peer in self
+            .chat
+            .conversations()
+            .iter()
+            .copied()
+            .chain(self.conversation)
+        {
+            if !tabs.contains(&peer) {
+                tabs.push(peer);
+            }
+        }
+        if !tabs.is_empty() {
+            egui::TopBottomPanel::top("conversations").show(ctx, |ui| {
+                ui.horizontal_wrapped(|ui| {
+                    if ui
+                        .selectable_label(self.conversation.is_none(), format!("#{}", self.channel))
+                        .clicked()
+                    {
+                        self.conversation = None;
+                    }
+                    for peer in tabs {
+                        let selected = self.conversation == Some(peer);
+                        if ui
+                            .selectable_label(selected, self.peer_label(&peer))
+                            .on_hover_text(self.peer_hover(&peer))
+                            .clicked()
+                        {
+                            self.conversation = Some(peer);
+                        }
+                    }
+                });
+            });
+        }
+        let mut leave = None;
+        let mut join = false;
+        egui::SidePanel::left("channels").show(ctx, |ui| {
+            let channels = std::iter::once(DEFAULT_CHANNEL.to_string())
+                .chain(self.chat.channels().iter().cloned())
+                .collect::<Vec<String>>();
+            for channel in channels {
+                ui.horizontal(|ui| {
+                    let selected = self.conversation.is_none() && self.channel == channel;
+                    if ui
+                        .selectable_label(selected, format!("#{}", channel))
+                        .clicked()
+                    {
+                        self.channel = channel.to_owned();
+                        self.conversation = None;
+                    }
+                    if channel != DEFAULT_CHANNEL
+                        && ui.small_button("✖").on_hover_text("Leave").clicked()
+                    {
+                        leave = Some(channel);
+                    }
+                });
+            }
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_channel)
+                        .desired_width(80.0)
+                        .hint_text("channel")
+                        .id(egui::Id::new("channel_input")),
                 );
-                ui.label(format!("{}:{}", self.chat.ip, self.chat.port));
-                ui.label(&self.chat.db_status);
+                join = ui.small_button("+").on_hover_text("Join").clicked();
             });
         });
+        if let Some(channel) = leave {
+            self.leave_channel(&channel);
+        }
+        if join {
+            self.join_channel();
+        }
+        let mut attach = false;
         egui::TopBottomPanel::bottom("my_panel").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.small_button("📎").on_hover_text("Send a file").clicked() {
+                    self.attach = match self.attach {
+                        Some(_) => None,
+                        None => Some(String::new()),
+                    };
+                }
+                if let Some(path) = &mut self.attach {
+                    ui.add(
+                        egui::TextEdit::singleline(path)
+                            .hint_text("path to a file")
+                            .id(egui::Id::new("attach_input")),
+                    );
+                    attach = ui.small_button("Send").clicked();
+                } else if !ctx.input().raw.hovered_files.is_empty() {
+                    ui.label(match self.conversation {
+                        Some(node) => format!("Drop to send to {}", self.chat.peer_name(&node)),
+                        None => format!("Drop to send to #{}", self.channel),
+                    });
+                }
+            });
             let message_box = ui.add(
                 egui::TextEdit::multiline(&mut self.text)
                     .desired_width(f32::INFINITY)
                     .text_style(egui::TextStyle::Heading)
                     .id(egui::Id::new("text_input")),
             );
-            message_box.request_focus();
+            if self.settings.is_none()
+                && self.search.is_none()
+                && !ui.memory().has_focus(Id::new("channel_input"))
+                && !ui.memory().has_focus(Id::new("attach_input"))
+            {
+                message_box.request_focus();
+            }
         });
 
+        if attach {
+            self.send_attachment();
+        }
+        let mut open_direct = None;
+        let mut control = None;
+        let mut scrolled = false;
+        // The first row is in sight, or there is none.
+        let mut at_top = true;
+        let mut first_row = None;
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::ScrollArea::vertical()
                 .max_width(f32::INFINITY)
                 .stick_to_bottom()
                 .show(ui, |ui| {
-                    self.chat.history.iter().for_each(|m| {
-                        let (direction, fill_color) = match &m.0 {
-                            x if x == &self.chat.ip => (
+                    let conversation = self.conversation;
+                    let channel = &self.channel;
+                    let thread = self
+                        .chat
+                        .history()
+                        .iter()
+                        .filter(|m| m.is_in(conversation, channel));
+                    let width = ui.available_width();
+                    if width != self.rows_width {
+                        self.row_heights.clear();
+                        self.rows_width = width;
+                    }
+                    let visible = ui.clip_rect();
+                    let spacing = ui.spacing().item_spacing.y;
+                    for m in thread {
+                        let key = (m.node, m.id);
+                        let top = ui.available_rect_before_wrap().top();
+                        if first_row.is_none() {
+                            first_row = Some(key);
+                            at_top = top >= visible.top();
+                        }
+                        if self.anchor == Some(key) {
+                            ui.scroll_to_cursor(egui::Align::TOP);
+                            self.anchor = None;
+                        }
+                        let found = self.found == Some(key);
+                        match self.row_heights.get(&key) {
+                            Some(&height)
+                                if !(found && self.scroll_to_found)
+                                    && (top + height < visible.top() || top > visible.bottom()) =>
+                            {
+                                ui.allocate_space(egui::vec2(0.0, height));
+                                continue;
+                            }
+                            _ => (),
+                        }
+                        let (direction, fill_color) = match &m.node {
+                            _ if found => (
+                                match m.node == self.chat.node() {
+                                    true => egui::Direction::RightToLeft,
+                                    false => egui::Direction::LeftToRight,
+                                },
+                                FOUND,
+                            ),
+                            x if x == &self.chat.node() => (
                                 egui::Direction::RightToLeft,
                                 egui::Color32::from_rgb(70, 70, 70),
                             ),
@@ -125,30 +1035,190 @@ impl ChatApp {
                                 egui::Align::Min,
                             ),
                             |line| {
-                                if m.0 != self.chat.ip {
-                                    line.add(
-                                        egui::Label::new(&m.0)
-                                            .wrap(false)
-                                            .strong()
-                                            .sense(Sense::click()),
-                                    )
-                                    .clicked();
+                                if m.node != self.chat.node() {
+                                    let (name, hover) = match self.chat.peers().get(&m.node) {
+                                        Some(peer) if !peer.name.is_empty() => (
+                                            format!("{} {}", peer.presence.icon(), peer.name),
+                                            format!("{} {}", peer.presence, peer.status),
+                                        ),
+                                        _ => (m.name.to_owned(), "gone".to_string()),
+                                    };
+                                    if line
+                                        .add(
+                                            egui::Label::new(name)
+                                                .wrap(false)
+                                                .strong()
+                                                .sense(Sense::click()),
+                                        )
+                                        .on_hover_text(format!(
+                                            "{}\nSend a direct message",
+                                            hover.trim()
+                                        ))
+                                        .clicked()
+                                    {
+                                        open_direct = Some(m.node);
+                                    }
                                 }
-                                if line
+                                let bubble = line
                                     .add(
-                                        egui::Button::new(&m.1)
+                                        egui::Button::new(&m.text)
                                             .wrap(true)
                                             .text_style(egui::TextStyle::Heading)
                                             .fill(fill_color),
                                     )
-                                    .clicked()
+                                    .on_hover_text(sent_at(m.timestamp));
+                                if found && self.scroll_to_found {
+                                    bubble.scroll_to_me(egui::Align::Center);
+                                    scrolled = true;
+                                }
+                                if bubble.clicked() {
+                                    self.text.push_str(&m.text);
+                                }
+                                if let Some(delivery) = m.delivery {
+                                    line.label(delivery.icon())
+                                        .on_hover_text(format!("{:?}", delivery));
+                                }
+                                if let Some(t) =
+                                    m.file.and_then(|id| self.chat.transfers().get(&id))
                                 {
-                                    self.text.push_str(&m.1);
+                                    if let Some(state) = draw_transfer(line, t) {
+                                        control = Some((t.id, state));
+                                    }
                                 }
                             },
                         );
-                    });
+                        if let Some(Some((texture, size))) =
+                            m.file.and_then(|id| self.previews.get(&id))
+                        {
+                            ui.with_layout(
+                                egui::Layout::from_main_dir_and_cross_align(
+                                    direction,
+                                    egui::Align::Min,
+                                ),
+                                |line| line.image(*texture, *size),
+                            );
+                        }
+                        let height = ui.available_rect_before_wrap().top() - top - spacing;
+                        self.row_heights.insert(key, height);
+                    }
                 });
         });
+        if scrolled {
+            self.scroll_to_found = false;
+        }
+        let channel = self.channel.to_owned();
+        if at_top && self.chat.load_older(self.conversation, &channel) {
+            self.anchor = first_row;
+            ctx.request_repaint();
+        }
+        if open_direct.is_some() {
+            self.conversation = open_direct;
+        }
+        match control {
+            Some((id, TransferState::Running)) => self.chat.resume_transfer(id),
+            Some((id, TransferState::Paused)) => self.chat.pause_transfer(id),
+            Some((id, TransferState::Cancelled)) => self.chat.cancel_transfer(id),
+            _ => (),
+        }
     }
 }
+
+/// Progress and buttons of a file in the history. Returns the state the user asked for.
+fn draw_transfer(ui: &mut Ui, t: &Transfer) -> Option<TransferState> {
+    let mut wanted = None;
+    match t.state {
+        TransferState::Offered => {
+            if ui.small_button("⬇").on_hover_text("Download").clicked() {
+                wanted = Some(TransferState::Running);
+            }
+        }
+        TransferState::Running | TransferState::Paused | TransferState::Failed => {
+            ui.add(
+                egui::ProgressBar::new(t.progress())
+                    .desired_width(80.0)
+                    .show_percentage(),
+            );
+            if t.incoming {
+                match t.state {
+                    TransferState::Running => {
+                        if ui.small_button("⏸").on_hover_text("Pause").clicked() {
+                            wanted = Some(TransferState::Paused);
+                        }
+                    }
+                    _ => {
+                        let hint = match t.state {
+                            TransferState::Failed => "Failed, try again",
+                            _ => "Resume",
+                        };
+                        if ui.small_button("▶").on_hover_text(hint).clicked() {
+                            wanted = Some(TransferState::Running);
+                        }
+                    }
+                }
+            }
+        }
+        TransferState::Done => {
+            ui.label("💾").on_hover_text(t.path.display().to_string());
+        }
+        TransferState::Cancelled => {
+            ui.label("Cancelled");
+        }
+    }
+    if !matches!(t.state, TransferState::Done | TransferState::Cancelled)
+        && ui.small_button("✖").on_hover_text("Cancel").clicked()
+    {
+        wanted = Some(TransferState::Cancelled);
+    }
+    wanted
+}
+
+/// Text with the `matches` marked, sensing clicks.
+fn highlighted(ui: &mut Ui, text: &str, matches: &[Range<usize>]) -> Response {
+    let plain = TextFormat::simple(TextStyle::Body, ui.visuals().text_color());
+    let marked = TextFormat {
+        color: Color32::WHITE,
+        background: FOUND,
+        ..plain
+    };
+    let mut job = LayoutJob {
+        wrap_width: ui.available_width(),
+        ..Default::default()
+    };
+    let mut at = 0;
+    for range in matches {
+        if let (Some(before), Some(found)) = (text.get(at..range.start), text.get(range.clone())) {
+            job.append(before, 0.0, plain);
+            job.append(found, 0.0, marked);
+            at = range.end;
+        }
+    }
+    job.append(&text[at..], 0.0, plain);
+    let galley = ui.fonts().layout_job(job);
+    let (rect, response) = ui.allocate_exact_size(galley.size(), Sense::click());
+    ui.painter().galley(rect.min, galley);
+    response
+}
+
+/// Size and pixels of a preview, `None` if the image doesn't decode.
+type Thumbnail = Option<((usize, usize), Vec<Color32>)>;
+
+fn thumbnail(path: &Path) -> Thumbnail {
+    let image = image::open(path)
+        .ok()?
+        .thumbnail(PREVIEW, PREVIEW)
+        .to_rgba8();
+    let size = (image.width() as usize, image.height() as usize);
+    let pixels = image
+        .pixels()
+        .map(|p| Color32::from_rgba_unmultiplied(p[0], p[1], p[2], p[3]))
+        .collect();
+    Some((size, pixels))
+}
+
+fn sent_at(timestamp: u64) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,327 @@
+use super::message::{Command, Message};
+use super::peers::NodeId;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use log::{info, warn};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const KEY_LEN: usize = 32;
+const IDENTITY_FILE: &str = "identity.key";
+const TRUSTED_FILE: &str = "trusted.keys";
+const SESSION_INFO: &[u8] = b"udp_chat session v1";
+
+struct Session {
+    key: PublicKey,
+    cipher: XChaCha20Poly1305,
+}
+
+/// Our identity and the keys agreed with every peer.
+///
+/// Each pair of peers derives a shared key from their static X25519 keys,
+/// announced in `Enter`. Texts are sealed with XChaCha20-Poly1305; the nonce
+/// comes from the message id, timestamp and command, so a repeated message
+/// encrypts to the very same bytes and its parts can be resent one by one.
+pub struct Keyring {
+    secret: StaticSecret,
+    pub public: PublicKey,
+    /// Who we are to peers, whatever address we use. Follows from `public`.
+    pub node: NodeId,
+    sessions: HashMap<NodeId, Session>,
+    trusted: HashSet<[u8; KEY_LEN]>,
+    dir: Option<PathBuf>,
+}
+
+impl Keyring {
+    /// Reads the identity from `dir`, creating one on the first start.
+    pub fn load(dir: Option<PathBuf>) -> Self {
+        let secret = dir
+            .as_ref()
+            .and_then(|dir| std::fs::read(dir.join(IDENTITY_FILE)).ok())
+            .and_then(|bytes| <[u8; KEY_LEN]>::try_from(bytes).ok())
+            .map(StaticSecret::from)
+            .unwrap_or_else(|| {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                if let Some(dir) = &dir {
+                    match write_private(&dir.join(IDENTITY_FILE), &secret.to_bytes()) {
+                        Ok(_) => info!("New identity key created."),
+                        Err(err) => warn!("Identity! {}", err),
+                    }
+                }
+                secret
+            });
+        let public = PublicKey::from(&secret);
+        let trusted = dir
+            .as_ref()
+            .and_then(|dir| std::fs::read_to_string(dir.join(TRUSTED_FILE)).ok())
+            .map(|text| text.lines().filter_map(key_from_hex).collect())
+            .unwrap_or_default();
+        Keyring {
+            node: NodeId::from_key(public.as_bytes()),
+            public,
+            secret,
+            sessions: HashMap::new(),
+            trusted,
+            dir,
+        }
+    }
+
+    /// Agrees on a key with a peer. A key that is not the one its node id
+    /// comes from is refused, and an agreed key is never replaced.
+    /// Returns `false` if the key was refused.
+    pub fn handshake(&mut self, node: NodeId, key: [u8; KEY_LEN]) -> bool {
+        if NodeId::from_key(&key) != node {
+            return false;
+        }
+        if node == self.node || self.sessions.contains_key(&node) {
+            return true;
+        }
+        let key = PublicKey::from(key);
+        let shared = self.secret.diffie_hellman(&key);
+        let (a, b) = match self.public.as_bytes() < key.as_bytes() {
+            true => (self.public.as_bytes(), key.as_bytes()),
+            false => (key.as_bytes(), self.public.as_bytes()),
+        };
+        let salt = [&a[..], &b[..]].concat();
+        let mut okm = [0; KEY_LEN];
+        Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+            .expand(SESSION_INFO, &mut okm)
+            .expect("32 bytes is a valid HKDF length");
+        let cipher = XChaCha20Poly1305::new(&okm.into());
+        self.sessions.insert(node, Session { key, cipher });
+        true
+    }
+
+    pub fn forget(&mut self, node: &NodeId) {
+        self.sessions.remove(node);
+    }
+
+    pub fn peer_key(&self, node: &NodeId) -> Option<[u8; KEY_LEN]> {
+        self.sessions.get(node).map(|s| s.key.to_bytes())
+    }
+
+    pub fn seal(&self, node: &NodeId, message: &Message) -> Option<Message> {
+        let session = self.sessions.get(node)?;
+        let aad = associated_data(message);
+        let data = session
+            .cipher
+            .encrypt(
+                &nonce(message),
+                Payload {
+                    msg: &message.data,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        Some(message.with_data(data))
+    }
+
+    pub fn open(&self, node: &NodeId, message: &Message) -> Option<Message> {
+        let session = self.sessions.get(node)?;
+        let aad = associated_data(message);
+        let data = session
+            .cipher
+            .decrypt(
+                &nonce(message),
+                Payload {
+                    msg: &message.data,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        Some(message.with_data(data))
+    }
+
+    pub fn is_trusted(&self, key: &[u8; KEY_LEN]) -> bool {
+        self.trusted.contains(key)
+    }
+
+    /// Marks a key as verified out of band and remembers the choice.
+    pub fn set_trusted(&mut self, key: [u8; KEY_LEN], trusted: bool) {
+        match trusted {
+            true => self.trusted.insert(key),
+            false => self.trusted.remove(&key),
+        };
+        if let Some(dir) = &self.dir {
+            let text: String = self
+                .trusted
+                .iter()
+                .map(|key| format!("{}\n", to_hex(key)))
+                .collect();
+            if let Err(err) = std::fs::write(dir.join(TRUSTED_FILE), text) {
+                warn!("Trusted keys! {}", err);
+            }
+        }
+    }
+}
+
+/// Short, human-comparable digest of a public key.
+pub fn fingerprint(key: &[u8; KEY_LEN]) -> String {
+    Sha256::digest(key)[..16]
+        .chunks(2)
+        .map(to_hex)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn nonce(message: &Message) -> XNonce {
+    let mut nonce = [0; 24];
+    nonce[..8].copy_from_slice(&message.id.to_be_bytes());
+    nonce[8..16].copy_from_slice(&message.timestamp.to_be_bytes());
+    nonce[16] = sealed_command(message);
+    nonce.into()
+}
+
+/// What a sealed message is, a `Repeat` being the `Text` it repeats.
+fn sealed_command(message: &Message) -> u8 {
+    match message.command {
+        Command::Repeat => Command::Text,
+        command => command,
+    }
+    .to_code()
+}
+
+fn associated_data(message: &Message) -> Vec<u8> {
+    let mut aad = [message.id.to_be_bytes(), message.timestamp.to_be_bytes()].concat();
+    aad.push(sealed_command(message));
+    aad.push(message.private as u8);
+    aad.extend(message.channel.as_bytes());
+    aad
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key_from_hex(text: &str) -> Option<[u8; KEY_LEN]> {
+    let text = text.trim();
+    if text.len() != KEY_LEN * 2 {
+        return None;
+    }
+    let bytes = (0..KEY_LEN)
+        .map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    bytes.try_into().ok()
+}
+
+fn write_private(path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, bytes)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two keyrings that agreed on a key.
+    fn pair() -> (Keyring, Keyring) {
+        let mut alice = Keyring::load(None);
+        let mut bob = Keyring::load(None);
+        assert!(alice.handshake(bob.node, bob.public.to_bytes()));
+        assert!(bob.handshake(alice.node, alice.public.to_bytes()));
+        (alice, bob)
+    }
+
+    #[test]
+    fn opens_what_was_sealed() {
+        let (alice, bob) = pair();
+        let message = Message::text("hello").in_channel("lunch");
+        let sealed = alice.seal(&bob.node, &message).unwrap();
+        assert_ne!(sealed.data, message.data);
+        let opened = bob.open(&alice.node, &sealed).unwrap();
+        assert_eq!(opened.read_text(), "hello");
+        // A repeat is sealed as the text it repeats.
+        let repeat = Message {
+            command: Command::Repeat,
+            ..sealed.clone()
+        };
+        assert_eq!(bob.open(&alice.node, &repeat).unwrap().read_text(), "hello");
+        // Nobody else can open it.
+        let (eve, _) = pair();
+        assert!(eve.open(&alice.node, &sealed).is_none());
+    }
+
+    #[test]
+    fn refuses_another_context() {
+        let (alice, bob) = pair();
+        let sealed = alice
+            .seal(&bob.node, &Message::text("hello").in_channel("lunch"))
+            .unwrap();
+        let private = Message {
+            private: true,
+            ..sealed.clone()
+        };
+        assert!(bob.open(&alice.node, &private).is_none());
+        let moved = sealed.clone().in_channel("general");
+        assert!(bob.open(&alice.node, &moved).is_none());
+        let later = Message {
+            timestamp: sealed.timestamp + 1,
+            ..sealed.clone()
+        };
+        assert!(bob.open(&alice.node, &later).is_none());
+    }
+
+    #[test]
+    fn nonces_differ_by_command() {
+        let text = Message::text("hello");
+        let mut nonces = HashSet::new();
+        for command in [
+            Command::Text,
+            Command::Ack,
+            Command::Request,
+            Command::Cancel,
+            Command::Exit,
+            Command::Heartbeat,
+            Command::Name,
+        ] {
+            let message = Message {
+                command,
+                ..text.clone()
+            };
+            assert!(nonces.insert(nonce(&message)));
+        }
+        let repeat = Message {
+            command: Command::Repeat,
+            ..text.clone()
+        };
+        assert!(!nonces.insert(nonce(&repeat)));
+        // So a command can't pass for another one with the same id and time.
+        let (alice, bob) = pair();
+        let ack = Message::ack(1);
+        let sealed = alice.seal(&bob.node, &ack).unwrap();
+        let exit = Message {
+            command: Command::Exit,
+            ..sealed
+        };
+        assert!(bob.open(&alice.node, &exit).is_none());
+    }
+
+    #[test]
+    fn refuses_keys_of_other_nodes() {
+        let (mut alice, bob) = pair();
+        let eve = Keyring::load(None);
+        assert!(!alice.handshake(bob.node, eve.public.to_bytes()));
+        assert!(!alice.handshake(eve.node, bob.public.to_bytes()));
+        assert!(alice.peer_key(&eve.node).is_none());
+        // The agreed key stays, and so does what was sealed with it.
+        assert_eq!(alice.peer_key(&bob.node), Some(bob.public.to_bytes()));
+        assert!(alice.handshake(bob.node, bob.public.to_bytes()));
+        let sealed = bob.seal(&alice.node, &Message::text("hi")).unwrap();
+        assert_eq!(alice.open(&bob.node, &sealed).unwrap().read_text(), "hi");
+        // No session with ourselves.
+        assert!(alice.handshake(alice.node, alice.public.to_bytes()));
+        assert!(alice.peer_key(&alice.node).is_none());
+    }
+}
@@ -0,0 +1,96 @@
+use super::message::Message;
+use super::peers::NodeId;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Wait before the first retransmission, doubled on every attempt.
+pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Transmissions before a message is given up on.
+pub const MAX_ATTEMPTS: u8 = 5;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Delivery {
+    Sending,
+    Delivered,
+    Failed,
+}
+
+impl Delivery {
+    pub fn icon(&self) -> &'static str {
+        match self {
+            Delivery::Sending => "…",
+            Delivery::Delivered => "✔",
+            Delivery::Failed => "✖",
+        }
+    }
+}
+
+struct Outgoing {
+    message: Message,
+    waiting: HashSet<NodeId>,
+    attempts: u8,
+    due: Instant,
+}
+
+/// Sent messages that some recipients haven't acknowledged yet.
+#[derive(Default)]
+pub struct Outbox {
+    pending: HashMap<u64, Outgoing>,
+}
+
+impl Outbox {
+    /// Starts tracking a message that was just transmitted.
+    pub fn push(&mut self, message: Message, recipients: HashSet<NodeId>) -> Delivery {
+        if recipients.is_empty() {
+            return Delivery::Delivered;
+        }
+        self.pending.insert(
+            message.id,
+            Outgoing {
+                message,
+                waiting: recipients,
+                attempts: 1,
+                due: Instant::now() + RETRY_DELAY,
+            },
+        );
+        Delivery::Sending
+    }
+
+    /// Registers an acknowledgement. Returns `true` when the last recipient confirmed.
+    pub fn ack(&mut self, node: NodeId, id: u64) -> bool {
+        if let Some(outgoing) = self.pending.get_mut(&id) {
+            outgoing.waiting.remove(&node);
+            if outgoing.waiting.is_empty() {
+                self.pending.remove(&id);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Messages to retransmit now with their silent recipients,
+    /// and ids of messages that ran out of attempts.
+    pub fn due(&mut self) -> (Vec<(Message, Vec<NodeId>)>, Vec<u64>) {
+        let now = Instant::now();
+        let failed: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, o)| o.attempts >= MAX_ATTEMPTS && o.due <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        failed.iter().for_each(|id| {
+            self.pending.remove(id);
+        });
+        let retries = self
+            .pending
+            .values_mut()
+            .filter(|o| o.due <= now)
+            .map(|o| {
+                o.due = now + RETRY_DELAY * 2u32.pow(o.attempts as u32);
+                o.attempts += 1;
+                (o.message.clone(), o.waiting.iter().copied().collect())
+            })
+            .collect();
+        (retries, failed)
+    }
+}
@@ -0,0 +1,56 @@
+use super::delivery::Delivery;
+use super::network::ConnectionState;
+use super::peers::{NodeId, Presence};
+use super::transfer::TransferState;
+use super::HistoryEntry;
+use std::path::PathBuf;
+
+/// Something that happened in the chat, for front-ends to react to.
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    /// A text or a file offer was sent or received.
+    Message(HistoryEntry),
+    /// Our message `id` got acknowledged by everyone or was given up on.
+    Delivery {
+        id: u64,
+        delivery: Delivery,
+    },
+    PeerEntered {
+        node: NodeId,
+        name: String,
+    },
+    PeerLeft {
+        node: NodeId,
+    },
+    /// A peer became away or busy, or changed its status text.
+    Presence {
+        node: NodeId,
+        presence: Presence,
+        status: String,
+    },
+    /// Someone announced a peer with a key that is not its own.
+    /// The peer keeps the key we agreed on.
+    KeyRefused {
+        node: NodeId,
+    },
+    Joined {
+        node: NodeId,
+        channel: String,
+    },
+    Left {
+        node: NodeId,
+        channel: String,
+    },
+    /// We went online, offline or moved to another address.
+    Connection(ConnectionState),
+    /// A transfer finished, failed or was cancelled.
+    Transfer {
+        id: u64,
+        state: TransferState,
+    },
+    /// A file could not be read to be offered.
+    OfferFailed {
+        path: PathBuf,
+        error: String,
+    },
+}
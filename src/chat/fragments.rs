@@ -0,0 +1,176 @@
+use super::message::Message;
+use super::peers::NodeId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the next part before asking to repeat the missing ones.
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Requests to repeat before an incomplete message is dropped.
+pub const MAX_RETRIES: u8 = 3;
+/// Most parts of a message we put back together.
+pub const MAX_PARTS: u16 = 256;
+/// Messages put together at once per sender. A new one beyond that
+/// replaces the one that waited longest.
+pub const MAX_PENDING: usize = 8;
+/// Messages put together at once from everyone, as senders are only
+/// what the parts claim.
+pub const MAX_PENDING_ALL: usize = 64;
+
+struct Pending {
+    /// First part to arrive, its header is shared by all of them.
+    head: Message,
+    parts: Vec<Option<Vec<u8>>>,
+    updated: Instant,
+    retries: u8,
+}
+
+impl Pending {
+    fn missing(&self) -> Vec<u16> {
+        self.parts
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.is_none())
+            .map(|(i, _)| i as u16)
+            .collect()
+    }
+}
+
+/// Collects parts of split messages until they are complete.
+#[derive(Default)]
+pub struct Assembler {
+    pending: HashMap<(NodeId, u64), Pending>,
+}
+
+impl Assembler {
+    /// Stores a part. Returns the whole message once every part has arrived.
+    /// Parts of messages longer than `MAX_PARTS` are dropped.
+    pub fn insert(&mut self, node: NodeId, message: Message) -> Option<Message> {
+        if message.parts <= 1 {
+            return Some(message);
+        }
+        if message.parts > MAX_PARTS {
+            return None;
+        }
+        let key = (node, message.id);
+        if !self.pending.contains_key(&key) {
+            self.make_room(|n| *n == node, MAX_PENDING);
+            self.make_room(|_| true, MAX_PENDING_ALL);
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            head: message.with_data(vec![]),
+            parts: vec![None; message.parts as usize],
+            updated: Instant::now(),
+            retries: 0,
+        });
+        if let Some(slot) = pending.parts.get_mut(message.part as usize) {
+            *slot = Some(message.data);
+            pending.updated = Instant::now();
+        }
+        if pending.parts.iter().all(|p| p.is_some()) {
+            let pending = self.pending.remove(&key)?;
+            let parts = pending.parts.into_iter().flatten().collect();
+            return Some(Message::assemble(&pending.head, parts));
+        }
+        None
+    }
+
+    /// Drops the stalest of the messages from senders that `from` picks
+    /// if there are `max` of them already.
+    fn make_room(&mut self, from: impl Fn(&NodeId) -> bool, max: usize) {
+        let pending: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|((node, _), _)| from(node))
+            .map(|(key, p)| (*key, p.updated))
+            .collect();
+        if pending.len() >= max {
+            if let Some((stalest, _)) = pending.iter().min_by_key(|(_, updated)| *updated) {
+                self.pending.remove(stalest);
+            }
+        }
+    }
+
+    /// Missing parts of messages that stalled, grouped by sender and id.
+    /// Messages that ran out of retries are forgotten.
+    pub fn stalled(&mut self) -> Vec<(NodeId, u64, Vec<u16>)> {
+        let now = Instant::now();
+        self.pending
+            .retain(|_, p| p.retries < MAX_RETRIES || now - p.updated < FRAGMENT_TIMEOUT);
+        self.pending
+            .iter_mut()
+            .filter(|(_, p)| now - p.updated >= FRAGMENT_TIMEOUT)
+            .map(|((node, id), p)| {
+                p.retries += 1;
+                p.updated = now;
+                (*node, *id, p.missing())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::message::MAX_DATA;
+
+    #[test]
+    fn reassembles_in_any_order() {
+        let message = Message::text(&"abc".repeat(MAX_DATA));
+        let mut parts = message.split();
+        assert_eq!(parts.len(), 3);
+        parts.reverse();
+        let mut assembler = Assembler::default();
+        let node = NodeId(1);
+        assert!(assembler.insert(node, parts[0].clone()).is_none());
+        // A copy of a part already in.
+        assert!(assembler.insert(node, parts[0].clone()).is_none());
+        assert!(assembler.insert(node, parts[1].clone()).is_none());
+        let whole = assembler.insert(node, parts[2].clone()).unwrap();
+        assert_eq!((whole.part, whole.parts), (0, 1));
+        assert_eq!(whole.id, message.id);
+        assert_eq!(whole.read_text(), message.read_text());
+        assert!(assembler.pending.is_empty());
+    }
+
+    #[test]
+    fn keeps_senders_apart() {
+        let parts = Message::text(&"x".repeat(MAX_DATA + 1)).split();
+        let mut assembler = Assembler::default();
+        assert!(assembler.insert(NodeId(1), parts[0].clone()).is_none());
+        assert!(assembler.insert(NodeId(2), parts[1].clone()).is_none());
+        assert_eq!(assembler.pending.len(), 2);
+    }
+
+    #[test]
+    fn bounds_what_is_pending() {
+        let mut assembler = Assembler::default();
+        let mut part = Message::text("x");
+        part.parts = MAX_PARTS + 1;
+        assert!(assembler.insert(NodeId(1), part.clone()).is_none());
+        assert!(assembler.pending.is_empty());
+        part.parts = 2;
+        for id in 0..MAX_PENDING as u64 * 2 {
+            part.id = id;
+            assembler.insert(NodeId(1), part.clone());
+        }
+        assert_eq!(assembler.pending.len(), MAX_PENDING);
+        assert!(assembler
+            .pending
+            .contains_key(&(NodeId(1), MAX_PENDING as u64 * 2 - 1)));
+    }
+
+    #[test]
+    fn bounds_what_is_pending_from_everyone() {
+        let mut assembler = Assembler::default();
+        let mut part = Message::text("x");
+        part.parts = 2;
+        let senders = MAX_PENDING_ALL as u64 * 4;
+        for node in 0..senders {
+            part.id = node;
+            assembler.insert(NodeId(node), part.clone());
+        }
+        assert_eq!(assembler.pending.len(), MAX_PENDING_ALL);
+        let last = senders - 1;
+        assert!(assembler.pending.contains_key(&(NodeId(last), last)));
+    }
+}
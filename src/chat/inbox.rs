@@ -0,0 +1,128 @@
+use log::warn;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Messages the network tasks may queue before they have to wait for the front-end.
+pub const CAPACITY: usize = 4096;
+
+/// How well the front-end keeps up with the network.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct InboxStats {
+    /// Waiting to be handled right now.
+    pub queued: usize,
+    /// Most that ever waited at once.
+    pub peak: usize,
+    /// Handled so far.
+    pub handled: u64,
+    /// Times a network task found the queue full and had to wait.
+    pub stalls: u64,
+}
+
+impl fmt::Display for InboxStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} queued, {} at most, {} handled, {} stalls",
+            self.queued, self.peak, self.handled, self.stalls
+        )
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    peak: AtomicUsize,
+    handled: AtomicU64,
+    stalls: AtomicU64,
+}
+
+/// Queue from the network tasks to `UdpChat::receive`, which empties it on every call.
+pub struct Inbox<T> {
+    sender: mpsc::Sender<T>,
+    receiver: mpsc::Receiver<T>,
+    counters: Arc<Counters>,
+}
+
+/// The network tasks' end of an `Inbox`.
+pub struct Postman<T> {
+    sender: mpsc::Sender<T>,
+    counters: Arc<Counters>,
+}
+
+impl<T> Clone for Postman<T> {
+    fn clone(&self) -> Self {
+        Postman {
+            sender: self.sender.clone(),
+            counters: Arc::clone(&self.counters),
+        }
+    }
+}
+
+impl<T> Default for Inbox<T> {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel(CAPACITY);
+        Inbox {
+            sender,
+            receiver,
+            counters: Arc::default(),
+        }
+    }
+}
+
+impl<T> Inbox<T> {
+    pub fn postman(&self) -> Postman<T> {
+        Postman {
+            sender: self.sender.clone(),
+            counters: Arc::clone(&self.counters),
+        }
+    }
+
+    /// Everything queued so far.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut items = Vec::new();
+        while let Ok(item) = self.receiver.try_recv() {
+            items.push(item);
+        }
+        self.counters
+            .queued
+            .fetch_sub(items.len(), Ordering::Relaxed);
+        self.counters
+            .handled
+            .fetch_add(items.len() as u64, Ordering::Relaxed);
+        items
+    }
+
+    pub fn stats(&self) -> InboxStats {
+        InboxStats {
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            peak: self.counters.peak.load(Ordering::Relaxed),
+            handled: self.counters.handled.load(Ordering::Relaxed),
+            stalls: self.counters.stalls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T> Postman<T> {
+    /// Queues `item`, waiting while the queue is full.
+    /// Returns `false` if the inbox is gone.
+    pub async fn deliver(&self, item: T) -> bool {
+        // Counted first, so `drain` never sees more items than `queued`.
+        let queued = self.counters.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.counters.peak.fetch_max(queued, Ordering::Relaxed);
+        let sent = match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) => {
+                self.counters.stalls.fetch_add(1, Ordering::Relaxed);
+                warn!("Inbox is full, waiting for the front-end.");
+                self.sender.send(item).await.map_err(|_| ())
+            }
+            Err(TrySendError::Closed(_)) => Err(()),
+        };
+        if sent.is_err() {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent.is_ok()
+    }
+}
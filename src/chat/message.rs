@@ -1,9 +1,73 @@
-use crc::{Crc, CRC_16_IBM_SDLC};
+use super::crypto::KEY_LEN;
+use super::peers::{NodeId, Presence};
+use super::transfer::{HASH_LEN, MAX_FILE};
+use crc::{Crc, CRC_32_ISCSI};
 use enumn::N;
 use std::fmt;
 use std::time::SystemTime;
 
-pub const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+/// CRC-32C of the whole datagram, taken with the checksum itself zeroed.
+pub const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+/// Starts every datagram of ours, so stray packets on the port are not taken for messages.
+pub const MAGIC: [u8; 4] = *b"UDPc";
+/// Layout of the fixed header. New commands, capabilities and optional fields
+/// are added without changing it; older peers skip what they don't know.
+pub const VERSION: u8 = 2;
+/// magic, version, id, checksum, command, flags, part, number of parts,
+/// timestamp, sender and length of the optional fields that follow.
+pub const HEADER_LEN: usize = 41;
+/// Room everybody is in and nobody can leave.
+pub const DEFAULT_CHANNEL: &str = "general";
+/// Longest channel name in bytes.
+pub const MAX_CHANNEL: usize = 32;
+/// Longest custom status in characters.
+pub const MAX_STATUS: usize = 64;
+/// Longest nickname in characters, so an `Enter` stays in one datagram.
+pub const MAX_NAME: usize = 32;
+/// Flag of a message meant for a single peer.
+const PRIVATE: u8 = 0b0000_0001;
+/// Largest piece of `data` carried by a single datagram.
+pub const MAX_DATA: usize = 1024;
+/// Longest text in bytes, so it fits in `MAX_PARTS` once sealed.
+pub const MAX_TEXT: usize = 255 * MAX_DATA;
+
+/// Optional header fields, each written as kind, length and value.
+#[derive(Debug, PartialEq, Copy, Clone, N)]
+#[repr(u8)]
+enum Field {
+    /// Room of the message, left out for the default one.
+    Channel = 1,
+    /// What the sender supports, sent with `Enter`.
+    Capabilities = 2,
+}
+
+/// Features a peer announces in `Enter`, so newer ones are only used with
+/// peers that understand them.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    // The two lowest bits stood for rooms and presence, which every peer
+    // reading this version has anyway.
+    pub const FILES: Capabilities = Capabilities(1 << 2);
+    /// Everything this version can do.
+    pub const OURS: Capabilities = Capabilities::FILES;
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Why a datagram was not read as a message.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Rejected {
+    /// Not ours at all.
+    Foreign,
+    /// Ours, but from another protocol version than we can read.
+    Version(u8),
+    /// Ours, but cut short or inconsistent.
+    Malformed,
+}
 
 #[derive(Debug, PartialEq, Copy, Clone, N)]
 #[repr(u8)]
@@ -15,6 +79,16 @@ pub enum Command {
     AskToRepeat,
     Repeat,
     Exit,
+    Ack,
+    Join,
+    Leave,
+    Offer,
+    Request,
+    Chunk,
+    Cancel,
+    Heartbeat,
+    /// Nickname and capabilities of a peer we already know.
+    Name,
     Error,
 }
 
@@ -22,6 +96,23 @@ impl Command {
     pub fn to_code(self) -> u8 {
         self as u8
     }
+    /// Sent to a single peer sealed with the key we share, see `Keyring`.
+    pub fn is_sealed(self) -> bool {
+        matches!(
+            self,
+            Command::Text
+                | Command::Repeat
+                | Command::Offer
+                | Command::Chunk
+                | Command::AskToRepeat
+                | Command::Ack
+                | Command::Request
+                | Command::Cancel
+                | Command::Exit
+                | Command::Name
+                | Command::Heartbeat
+        )
+    }
     pub fn from_code(code: u8) -> Self {
         Command::n(code).unwrap_or(Command::Error)
     }
@@ -29,9 +120,21 @@ impl Command {
 
 #[derive(Debug, Clone)]
 pub struct Message {
-    pub id: u32,
-    checksum: u16,
+    /// Random, so it stays unique per sender even within the same second.
+    pub id: u64,
     pub command: Command,
+    /// Direct message to one peer, kept out of the public room.
+    pub private: bool,
+    pub part: u16,
+    pub parts: u16,
+    /// Seconds since UNIX epoch when the message was created.
+    pub timestamp: u64,
+    /// Who sent it, filled in when it goes out.
+    pub node: NodeId,
+    /// Room of a text, or the one being joined or left.
+    pub channel: String,
+    /// What the sender supports, only announced in `Enter` and `Name`.
+    pub capabilities: Capabilities,
     pub data: Vec<u8>,
 }
 
@@ -39,20 +142,17 @@ impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "\nMessage #{}\nChecksum: {}\n{:?}\n'{}'\n",
+            "\nMessage #{:016x} [{}/{}] @{} from {} #{}\n{:?}\n'{}'\n",
             self.id,
-            self.checksum,
+            self.part + 1,
+            self.parts,
+            self.timestamp,
+            self.node,
+            self.channel,
             self.command,
             match self.command {
-                Command::Text | Command::Damaged | Command::Repeat => string_from_be_u8(&self.data),
-                Command::AskToRepeat => u32::from_be_bytes(
-                    (0..4)
-                        .map(|i| *self.data.get(i).unwrap_or(&0))
-                        .collect::<Vec<u8>>()
-                        .try_into()
-                        .unwrap(),
-                )
-                .to_string(),
+                command if command.is_sealed() => format!("{} sealed bytes", self.data.len()),
+                Command::Heartbeat => format!("{:?}", self.read_heartbeat()),
                 _ => format!("{:?}", &self.data),
             }
         )
@@ -61,114 +161,548 @@ impl fmt::Display for Message {
 
 impl Message {
     pub fn new(command: Command, data: Vec<u8>) -> Self {
-        let id = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
-        let checksum = CRC.checksum(&data);
         Message {
-            id,
-            checksum,
+            id: rand::random(),
             command,
+            private: false,
+            part: 0,
+            parts: 1,
+            timestamp: now(),
+            node: NodeId::default(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            capabilities: Capabilities::default(),
             data,
         }
     }
-    pub fn retry_text(id: u32, text: &str) -> Self {
-        let data = be_u8_from_str(
-            text.to_owned()
-                .chars()
-                .filter(|c| !c.is_control())
-                .collect::<String>()
-                .as_ref(),
-        );
-        let checksum = CRC.checksum(&data);
+    pub fn retry_text(id: u64, timestamp: u64, text: &str) -> Self {
+        let data = be_u8_from_str(&clean_text(text));
         Message {
             id,
-            checksum,
             command: Command::Repeat,
+            private: false,
+            part: 0,
+            parts: 1,
+            timestamp,
+            node: NodeId::default(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            capabilities: Capabilities::default(),
             data,
         }
     }
 
+    /// Request to resend message `id`. Empty `parts` means the whole message.
+    pub fn ask_to_repeat(id: u64, parts: &[u16]) -> Self {
+        let mut data = id.to_be_bytes().to_vec();
+        parts
+            .iter()
+            .for_each(|part| data.extend(part.to_be_bytes()));
+        Message::new(Command::AskToRepeat, data)
+    }
+
+    /// Id and missing parts from an `AskToRepeat` request.
+    pub fn read_repeat_request(&self) -> (u64, Vec<u16>) {
+        let id = self.read_id();
+        let parts = self
+            .data
+            .get(8..)
+            .unwrap_or_default()
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        (id, parts)
+    }
+
+    /// Confirms that message `id` has arrived.
+    pub fn ack(id: u64) -> Self {
+        Message::new(Command::Ack, id.to_be_bytes().to_vec())
+    }
+
+    /// Id of the message or transfer a command refers to.
+    pub fn read_id(&self) -> u64 {
+        u64::from_be_bytes(
+            (0..8)
+                .map(|i| *self.data.get(i).unwrap_or(&0))
+                .collect::<Vec<u8>>()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
     pub fn empty() -> Self {
         Message {
             id: 0,
-            checksum: 0,
             command: Command::Empty,
+            private: false,
+            part: 0,
+            parts: 1,
+            timestamp: 0,
+            node: NodeId::default(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            capabilities: Capabilities::default(),
             data: [].to_vec(),
         }
     }
 
-    pub fn enter(name: &str) -> Self {
-        Message::new(Command::Enter, be_u8_from_str(name))
+    /// Announces our nickname and public key. With `reply` set, the receiver
+    /// answers with its own `Enter`.
+    pub fn enter(name: &str, key: &[u8; KEY_LEN], reply: bool) -> Self {
+        let mut data = vec![reply as u8];
+        data.extend(key);
+        data.extend(be_u8_from_str(&clean_name(name)));
+        Message {
+            capabilities: Capabilities::OURS,
+            ..Message::new(Command::Enter, data)
+        }
+    }
+
+    /// Reply flag, public key and nickname from an `Enter`.
+    pub fn read_enter(&self) -> Option<(bool, [u8; KEY_LEN], String)> {
+        let reply = *self.data.first()? != 0;
+        let key = self.data.get(1..=KEY_LEN)?.try_into().ok()?;
+        let name = clean_name(&string_from_be_u8(&self.data[KEY_LEN + 1..]));
+        Some((reply, key, name))
+    }
+
+    /// Our nickname and capabilities, sealed for peers who know us already:
+    /// unlike an `Enter`, nobody else can send it in our name.
+    pub fn name(name: &str) -> Self {
+        Message {
+            capabilities: Capabilities::OURS,
+            ..Message::new(Command::Name, be_u8_from_str(&clean_name(name)))
+        }
+    }
+
+    /// Nickname from a `Name`.
+    pub fn read_name(&self) -> String {
+        clean_name(&string_from_be_u8(&self.data))
+    }
+
+    /// Same message with another payload.
+    pub fn with_data(&self, data: Vec<u8>) -> Self {
+        Message {
+            data,
+            ..self.clone()
+        }
     }
 
     pub fn exit() -> Self {
         Message::new(Command::Exit, [].to_vec())
     }
 
+    /// Tells peers we are still around, and how available we are.
+    pub fn heartbeat(presence: Presence, status: &str) -> Self {
+        let mut data = vec![presence.to_code()];
+        let status: String = clean_text(status).chars().take(MAX_STATUS).collect();
+        data.extend(be_u8_from_str(&status));
+        Message::new(Command::Heartbeat, data)
+    }
+
+    /// Presence and status text from a `Heartbeat`.
+    pub fn read_heartbeat(&self) -> (Presence, String) {
+        let presence = Presence::from_code(*self.data.first().unwrap_or(&0));
+        let status = string_from_be_u8(self.data.get(1..).unwrap_or_default());
+        (presence, status)
+    }
+
+    /// Longer texts are cut at `MAX_TEXT`.
     pub fn text(text: &str) -> Self {
-        Message::new(
-            Command::Text,
-            be_u8_from_str(
-                text.to_owned()
-                    .chars()
-                    .filter(|c| !c.is_control())
-                    .collect::<String>()
-                    .as_ref(),
-            ),
-        )
+        let mut text = clean_text(text);
+        let mut end = text.len().min(MAX_TEXT);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        Message::new(Command::Text, be_u8_from_str(&text))
     }
 
-    pub fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
-        let id: u32 = u32::from_be_bytes([
-            *bytes.get(0)?,
-            *bytes.get(1)?,
-            *bytes.get(2)?,
-            *bytes.get(3)?,
-        ]);
-        let checksum = u16::from_be_bytes([*bytes.get(4)?, *bytes.get(5)?]);
-        let command = Command::from_code(u8::from_be_bytes([*bytes.get(6)?]));
-        let data = match bytes.len() {
-            0..=7 => [].to_vec(),
-            _ => bytes[7..].to_owned(),
-        };
-        if checksum == CRC.checksum(&data) || command == Command::Repeat {
-            Some(Message {
-                id,
-                checksum,
-                command,
-                data,
-            })
-        } else {
-            Some(Message {
-                id,
-                checksum,
-                command: Command::Damaged,
-                data,
+    /// Announces that we joined `channel`. With `reply` set, members answer
+    /// with their own `Join`.
+    pub fn join_channel(channel: &str, reply: bool) -> Self {
+        Message::new(Command::Join, vec![reply as u8]).in_channel(channel)
+    }
+
+    pub fn leave_channel(channel: &str) -> Self {
+        Message::new(Command::Leave, vec![]).in_channel(channel)
+    }
+
+    /// Reply flag of a `Join`.
+    pub fn wants_reply(&self) -> bool {
+        self.data.first() == Some(&1)
+    }
+
+    pub fn in_channel(self, channel: &str) -> Self {
+        Message {
+            channel: channel.to_string(),
+            ..self
+        }
+    }
+
+    /// Proposes a file. Its id becomes the id of the transfer.
+    pub fn offer(size: u64, hash: &[u8; HASH_LEN], name: &str) -> Self {
+        let mut data = size.to_be_bytes().to_vec();
+        data.extend(hash);
+        data.extend(be_u8_from_str(name));
+        Message::new(Command::Offer, data)
+    }
+
+    /// Size, SHA-256 and name of an offered file. Files larger than we
+    /// accept are no offer at all.
+    pub fn read_offer(&self) -> Option<(u64, [u8; HASH_LEN], String)> {
+        let size = u64::from_be_bytes(self.data.get(0..8)?.try_into().ok()?);
+        if size > MAX_FILE {
+            return None;
+        }
+        let hash = self.data.get(8..8 + HASH_LEN)?.try_into().ok()?;
+        let name = string_from_be_u8(&self.data[8 + HASH_LEN..]);
+        Some((size, hash, name))
+    }
+
+    /// Asks for chunks of transfer `id`. No chunks means the file is complete.
+    pub fn request(id: u64, chunks: &[u32]) -> Self {
+        let mut data = id.to_be_bytes().to_vec();
+        chunks
+            .iter()
+            .for_each(|chunk| data.extend(chunk.to_be_bytes()));
+        Message::new(Command::Request, data)
+    }
+
+    /// Transfer id and wanted chunks from a `Request`.
+    pub fn read_request(&self) -> (u64, Vec<u32>) {
+        let chunks = self
+            .data
+            .get(8..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        (self.read_id(), chunks)
+    }
+
+    /// A piece of a file. Every chunk gets its own id, so it is sealed with its own nonce.
+    pub fn chunk(id: u64, index: u32, bytes: &[u8]) -> Self {
+        let mut data = id.to_be_bytes().to_vec();
+        data.extend(index.to_be_bytes());
+        data.extend(bytes);
+        Message::new(Command::Chunk, data)
+    }
+
+    /// Transfer id, index and bytes of a `Chunk`.
+    pub fn read_chunk(&self) -> Option<(u64, u32, &[u8])> {
+        let index = u32::from_be_bytes(self.data.get(8..12)?.try_into().ok()?);
+        Some((self.read_id(), index, &self.data[12..]))
+    }
+
+    /// Stops transfer `id` on the other side.
+    pub fn cancel(id: u64) -> Self {
+        Message::new(Command::Cancel, id.to_be_bytes().to_vec())
+    }
+
+    pub fn direct(text: &str) -> Self {
+        Message {
+            private: true,
+            ..Message::text(text)
+        }
+    }
+
+    /// Splits the message into datagram-sized parts.
+    pub fn split(&self) -> Vec<Message> {
+        if self.data.len() <= MAX_DATA {
+            return vec![self.clone()];
+        }
+        let chunks = self.data.chunks(MAX_DATA);
+        let parts = chunks.len() as u16;
+        chunks
+            .enumerate()
+            .map(|(part, chunk)| Message {
+                id: self.id,
+                command: self.command,
+                private: self.private,
+                part: part as u16,
+                parts,
+                timestamp: self.timestamp,
+                node: self.node,
+                channel: self.channel.to_owned(),
+                capabilities: self.capabilities,
+                data: chunk.to_vec(),
             })
+            .collect()
+    }
+
+    /// Glues parts back together. They have to be complete and in order.
+    pub fn assemble(head: &Message, parts: Vec<Vec<u8>>) -> Self {
+        Message {
+            part: 0,
+            parts: 1,
+            ..head.with_data(parts.concat())
+        }
+    }
+
+    pub fn from_be_bytes(bytes: &[u8]) -> Result<Self, Rejected> {
+        if bytes.get(0..4) != Some(&MAGIC[..]) {
+            return Err(Rejected::Foreign);
+        }
+        match bytes.get(4) {
+            Some(&VERSION) => Message::read(bytes).ok_or(Rejected::Malformed),
+            Some(&version) => Err(Rejected::Version(version)),
+            None => Err(Rejected::Foreign),
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let header = bytes.get(..HEADER_LEN)?;
+        let checksum = u32::from_be_bytes(header[13..17].try_into().ok()?);
+        let mut digest = CRC.digest();
+        digest.update(&bytes[..13]);
+        digest.update(&[0; 4]);
+        digest.update(&bytes[17..]);
+        let intact = digest.finalize() == checksum;
+        let part = u16::from_be_bytes([header[19], header[20]]);
+        let parts = u16::from_be_bytes([header[21], header[22]]).max(1);
+        let mut message = Message {
+            id: u64::from_be_bytes(header[5..13].try_into().ok()?),
+            command: Command::Damaged,
+            private: header[18] & PRIVATE != 0,
+            part,
+            parts,
+            timestamp: u64::from_be_bytes(header[23..31].try_into().ok()?),
+            node: NodeId(u64::from_be_bytes(header[31..39].try_into().ok()?)),
+            channel: DEFAULT_CHANNEL.to_string(),
+            capabilities: Capabilities::default(),
+            data: Vec::new(),
+        };
+        if !intact {
+            // The header is what we have to go by to ask for it again.
+            if part >= parts {
+                message.part = 0;
+                message.parts = 1;
+            }
+            return Some(message);
+        }
+        if part >= parts {
+            return None;
+        }
+        let fields_end = HEADER_LEN + u16::from_be_bytes([header[39], header[40]]) as usize;
+        let mut fields = bytes.get(HEADER_LEN..fields_end)?;
+        while let [kind, len, rest @ ..] = fields {
+            let value = rest.get(..*len as usize)?;
+            match Field::n(*kind) {
+                Some(Field::Channel) => {
+                    message.channel = normalize_channel(std::str::from_utf8(value).ok()?)?
+                }
+                // One of another length is left out like an unknown one.
+                Some(Field::Capabilities) => {
+                    if let Ok(bits) = value.try_into() {
+                        message.capabilities = Capabilities(u32::from_be_bytes(bits));
+                    }
+                }
+                // Added after us: not needed to understand the message.
+                None => (),
+            }
+            fields = &rest[value.len()..];
+        }
+        if !fields.is_empty() {
+            return None;
         }
+        message.command = Command::from_code(header[17]);
+        message.data = bytes[fields_end..].to_owned();
+        Some(message)
     }
 
     pub fn to_be_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::<u8>::new();
+        let fields = self.fields();
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
         bytes.extend(self.id.to_be_bytes());
-        bytes.extend(self.checksum.to_be_bytes());
-        bytes.extend(self.command.to_code().to_be_bytes());
+        bytes.extend([0; 4]);
+        bytes.push(self.command.to_code());
+        bytes.push(if self.private { PRIVATE } else { 0 });
+        bytes.extend(self.part.to_be_bytes());
+        bytes.extend(self.parts.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.node.0.to_be_bytes());
+        bytes.extend((fields.len() as u16).to_be_bytes());
+        bytes.extend(fields);
         bytes.extend(self.data.to_owned());
-
+        let checksum = CRC.checksum(&bytes);
+        bytes[13..17].copy_from_slice(&checksum.to_be_bytes());
         bytes
     }
 
+    /// Optional header fields that differ from their defaults.
+    fn fields(&self) -> Vec<u8> {
+        let mut fields = Vec::new();
+        let mut field = |kind: Field, value: &[u8]| {
+            fields.push(kind as u8);
+            fields.push(value.len() as u8);
+            fields.extend(value);
+        };
+        if self.channel != DEFAULT_CHANNEL {
+            field(Field::Channel, self.channel.as_bytes());
+        }
+        if self.capabilities != Capabilities::default() {
+            field(Field::Capabilities, &self.capabilities.0.to_be_bytes());
+        }
+        fields
+    }
+
     pub fn read_text(&self) -> String {
         string_from_be_u8(&self.data)
     }
 }
 
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Lowercase channel name without the leading `#`, if it is a valid one.
+pub fn normalize_channel(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_CHANNEL
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(name)
+}
+
 pub fn string_from_be_u8(bytes: &[u8]) -> String {
     std::str::from_utf8(bytes).unwrap_or("UNKNOWN").to_string()
 }
 
+/// Drops control characters but keeps line breaks.
+fn clean_text(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n')
+        .collect()
+}
+
+/// Nickname on one line and at most `MAX_NAME` characters long.
+pub fn clean_name(name: &str) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME)
+        .collect();
+    name.trim_end().to_string()
+}
+
 fn be_u8_from_str(text: &str) -> Vec<u8> {
     text.trim().as_bytes().to_owned()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Message {
+        let mut message = Message::direct("héllo").in_channel("dev");
+        message.node = NodeId(0x0123_4567_89ab_cdef);
+        message.part = 1;
+        message.parts = 3;
+        message.capabilities = Capabilities::OURS;
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = sample();
+        let read = Message::from_be_bytes(&message.to_be_bytes()).unwrap();
+        assert_eq!(read.id, message.id);
+        assert_eq!(read.command, Command::Text);
+        assert!(read.private);
+        assert_eq!((read.part, read.parts), (1, 3));
+        assert_eq!(read.timestamp, message.timestamp);
+        assert_eq!(read.node, message.node);
+        assert_eq!(read.channel, "dev");
+        assert_eq!(read.capabilities, Capabilities::OURS);
+        assert_eq!(read.read_text(), "héllo");
+    }
+
+    #[test]
+    fn caps_names() {
+        let long = "ж".repeat(MAX_DATA);
+        let enter = Message::enter(&long, &[1; KEY_LEN], false);
+        assert_eq!(enter.split().len(), 1);
+        let (_, _, name) = enter.read_enter().unwrap();
+        assert_eq!(name.chars().count(), MAX_NAME);
+        // Whatever a peer sends.
+        let mut sent = vec![0];
+        sent.extend([1; KEY_LEN]);
+        sent.extend(format!("{}\nx", long).as_bytes());
+        let (_, _, name) = enter.with_data(sent).read_enter().unwrap();
+        assert_eq!(name, "ж".repeat(MAX_NAME));
+        let renamed = Message::name(&long).with_data(long.as_bytes().to_vec());
+        assert_eq!(renamed.read_name().chars().count(), MAX_NAME);
+        assert_eq!(clean_name(" a\tb "), "ab");
+    }
+
+    #[test]
+    fn rejects_foreign_and_other_versions() {
+        let mut bytes = sample().to_be_bytes();
+        assert_eq!(
+            Message::from_be_bytes(b"hello there").unwrap_err(),
+            Rejected::Foreign
+        );
+        bytes[4] = VERSION + 1;
+        assert_eq!(
+            Message::from_be_bytes(&bytes).unwrap_err(),
+            Rejected::Version(VERSION + 1)
+        );
+        bytes[4] = VERSION - 1;
+        assert_eq!(
+            Message::from_be_bytes(&bytes).unwrap_err(),
+            Rejected::Version(VERSION - 1)
+        );
+        assert_eq!(
+            Message::from_be_bytes(&bytes[..4]).unwrap_err(),
+            Rejected::Foreign
+        );
+        bytes[4] = VERSION;
+        assert_eq!(
+            Message::from_be_bytes(&bytes[..HEADER_LEN - 1]).unwrap_err(),
+            Rejected::Malformed
+        );
+    }
+
+    #[test]
+    fn damaged_keeps_header() {
+        let message = sample();
+        let mut bytes = message.to_be_bytes();
+        // The length of the fields, so they no longer parse either.
+        bytes[40] ^= 0xff;
+        let read = Message::from_be_bytes(&bytes).unwrap();
+        assert_eq!(read.command, Command::Damaged);
+        assert_eq!((read.id, read.node), (message.id, message.node));
+        assert_eq!((read.part, read.parts), (1, 3));
+        let last = bytes.len() - 1;
+        bytes[40] ^= 0xff;
+        bytes[last] ^= 1;
+        let read = Message::from_be_bytes(&bytes).unwrap();
+        assert_eq!(read.command, Command::Damaged);
+    }
+
+    #[test]
+    fn skips_odd_fields() {
+        let mut message = sample();
+        message.capabilities = Capabilities::default();
+        let mut bytes = message.to_be_bytes();
+        // A capabilities field one byte short, then an unknown one.
+        let odd = [Field::Capabilities as u8, 3, 0, 0, 4, 200, 1, 7];
+        let fields_end = HEADER_LEN + u16::from_be_bytes([bytes[39], bytes[40]]) as usize;
+        bytes.splice(fields_end..fields_end, odd);
+        let len = (fields_end - HEADER_LEN + odd.len()) as u16;
+        bytes[39..41].copy_from_slice(&len.to_be_bytes());
+        bytes[13..17].copy_from_slice(&[0; 4]);
+        let checksum = CRC.checksum(&bytes);
+        bytes[13..17].copy_from_slice(&checksum.to_be_bytes());
+        let read = Message::from_be_bytes(&bytes).unwrap();
+        assert_eq!(read.command, Command::Text);
+        assert_eq!(read.capabilities, Capabilities::default());
+        assert_eq!(read.channel, "dev");
+        assert_eq!(read.read_text(), "héllo");
+    }
+}
@@ -1,279 +1,1094 @@
+pub mod crypto;
+pub mod delivery;
+pub mod event;
+mod fragments;
+pub mod inbox;
 pub mod message;
+pub mod network;
+pub mod peers;
+pub mod store;
+pub mod transfer;
+pub mod transport;
 
-use eframe::epi::RepaintSignal;
+use crypto::Keyring;
+use delivery::{Delivery, Outbox};
+use event::ChatEvent;
+use fragments::Assembler;
+use inbox::{Inbox, InboxStats};
 use log::{info, warn};
-use message::{Command, Message};
-use rusqlite::Connection;
-use std::collections::HashSet;
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
-use std::path::PathBuf;
+use message::{clean_name, normalize_channel, Capabilities, Command, Message, DEFAULT_CHANNEL};
+use network::{ConnectionState, Discovery, Interface, Link};
+use peers::{NodeId, Peers, Presence};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::thread;
+use std::time::{Duration, Instant};
+use store::{Hit, Query, Store};
+use transfer::{size_label, Done, Transfers};
+use transport::{Datagram, Transport};
 
+/// How often the UI wakes up to run timers when nothing arrives.
+pub const TICK: Duration = Duration::from_millis(500);
+
+/// How often we tell peers we are still here, and announce ourselves to
+/// those that missed us.
+pub const HEARTBEAT: Duration = Duration::from_secs(10);
+/// Silence after which a peer is considered gone.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(35);
+/// How often we look whether the chosen interface changed or went away,
+/// and try again when offline.
+pub const NETWORK_CHECK: Duration = Duration::from_secs(3);
+/// Idle time after which we show up as away.
+pub const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Messages loaded at startup, and then per page when scrolling back.
+pub const PAGE: usize = 200;
+/// Copies of `Exit` sent on shutdown, in case some get lost.
+const EXIT_REPEATS: usize = 3;
+/// How long a `Repeat` we asked for is still welcome.
+const REPEAT_WINDOW: Duration = Duration::from_secs(30);
+/// How long ids of messages are kept to tell copies from new messages,
+/// well beyond the last retry.
+const SEEN_FOR: Duration = Duration::from_secs(10 * 60);
+
+/// Called from the network tasks when the front-end should call `receive`.
+pub type Wake = Arc<dyn Fn() + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
 pub enum Recepients {
-    One(Ipv4Addr),
+    One(NodeId),
     Peers,
     All,
 }
 
-pub struct UdpChat {
-    socket: Option<Arc<UdpSocket>>,
-    pub ip: Ipv4Addr,
-    pub port: usize,
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub node: NodeId,
     pub name: String,
-    sync_sender: mpsc::SyncSender<(Ipv4Addr, Message)>,
-    sync_receiver: mpsc::Receiver<(Ipv4Addr, Message)>,
-    pub message: Message,
-    pub history: Vec<(Ipv4Addr, String)>,
-    pub peers: HashSet<Ipv4Addr>,
-    db: Option<Connection>,
-    pub db_status: String,
+    pub timestamp: u64,
+    pub text: String,
+    pub channel: String,
+    /// The other side of a direct conversation, `None` in the public room.
+    pub direct: Option<NodeId>,
+    /// State of our own messages sent during this session.
+    pub delivery: Option<Delivery>,
+    /// Transfer of a file offered during this session.
+    pub file: Option<u64>,
+    /// Row in the history on disk, for those loaded from it. Our own clock
+    /// orders rows, unlike `timestamp` which is the sender's.
+    row: Option<i64>,
+}
+
+impl HistoryEntry {
+    /// Whether it belongs to the direct conversation with `direct`,
+    /// or without one to the room `channel`.
+    pub fn is_in(&self, direct: Option<NodeId>, channel: &str) -> bool {
+        self.direct == direct && (direct.is_some() || self.channel == channel)
+    }
+}
+
+/// The chat engine: networking, protocol and history, without any UI.
+///
+/// Front-ends call `prelude` once, then `receive` whenever `Wake` fires,
+/// which is at least every `TICK`, and learn about changes from `subscribe`.
+/// The network and file transfers are left to `Transport`, writes to the
+/// history to `Store`, so none of these calls wait on them. Only loading
+/// older history and searching read the disk right away.
+pub struct UdpChat {
+    transport: Transport,
+    ip: IpAddr,
+    port: u16,
+    interface: Interface,
+    discovery: Discovery,
+    /// Addresses we chat from, `ip` being the first one.
+    links: Vec<Link>,
+    connection: ConnectionState,
+    /// Set by the network tasks when the network fails under us.
+    broken: Arc<AtomicBool>,
+    /// Last look at the interfaces.
+    checked: Instant,
+    wake: Option<Wake>,
+    name: String,
+    /// What the network tasks received, by sender address.
+    inbox: Inbox<(SocketAddr, Message)>,
+    presence: Presence,
+    status: String,
+    /// Away was set by us after a while without activity, not by the user.
+    auto_away: bool,
+    active: Instant,
+    /// When we last sent a heartbeat.
+    beaten: Instant,
+    /// The part of the history loaded so far: the newest `PAGE` messages
+    /// and whatever was paged in or said since.
+    history: Vec<HistoryEntry>,
+    /// Nothing older is left on disk.
+    whole_history: bool,
+    /// Oldest row of the first page: any room has all its later rows loaded.
+    first_row: Option<i64>,
+    /// Rooms and direct conversations loaded back to their first message.
+    complete: HashSet<(Option<NodeId>, String)>,
+    /// Peers of the direct conversations in the history, on disk or not.
+    conversations: Vec<NodeId>,
+    peers: Peers,
+    /// Rooms we joined besides the default one.
+    channels: Vec<String>,
+    assembler: Assembler,
+    outbox: Outbox,
+    /// Messages taken in lately, by sender and id.
+    seen: HashMap<(NodeId, u64), Instant>,
+    /// Repeats we asked for, by sender and id. Nobody else gets to send one.
+    asked: HashMap<(NodeId, u64), Instant>,
+    keyring: Keyring,
+    transfers: Transfers,
+    /// Files being hashed, to be offered to these recipients in these channels.
+    offering: HashMap<u64, (Recepients, String)>,
+    /// Disk work finished by the transport.
+    disk: (mpsc::Sender<Done>, mpsc::Receiver<Done>),
+    downloads: PathBuf,
+    events: Vec<mpsc::Sender<ChatEvent>>,
+    store: Store,
 }
 impl UdpChat {
-    pub fn new(name: String, port: usize, db_path: Option<PathBuf>) -> Self {
-        let (tx, rx) = mpsc::sync_channel::<(Ipv4Addr, Message)>(0);
-        let (db, db_status) = match db_path {
-            Some(path) => (Connection::open(path).ok(), "DB: ready.".to_string()),
-            None => (None, "DB! offline".to_string()),
-        };
-        warn!("{}", db_status);
-        UdpChat {
-            socket: None,
-            ip: Ipv4Addr::UNSPECIFIED,
+    pub fn new(
+        name: String,
+        port: u16,
+        interface: Interface,
+        discovery: Discovery,
+        db_path: Option<PathBuf>,
+        keys_dir: Option<PathBuf>,
+        downloads: PathBuf,
+    ) -> io::Result<Self> {
+        let keyring = Keyring::load(keys_dir);
+        Ok(UdpChat {
+            transport: Transport::new()?,
+            ip: Ipv4Addr::UNSPECIFIED.into(),
             port,
-            name,
-            sync_sender: tx,
-            sync_receiver: rx,
-            message: Message::empty(),
-            history: Vec::<(Ipv4Addr, String)>::new(),
-            peers: HashSet::<Ipv4Addr>::new(),
-            db,
-            db_status,
-        }
+            interface,
+            discovery,
+            links: Vec::new(),
+            connection: ConnectionState::Binding,
+            broken: Arc::new(AtomicBool::new(false)),
+            checked: Instant::now(),
+            wake: None,
+            name: clean_name(&name),
+            inbox: Inbox::default(),
+            presence: Presence::default(),
+            status: String::new(),
+            auto_away: false,
+            beaten: Instant::now(),
+            active: Instant::now(),
+            history: Vec::<HistoryEntry>::new(),
+            whole_history: false,
+            first_row: None,
+            complete: HashSet::new(),
+            conversations: Vec::new(),
+            peers: Peers::default(),
+            channels: Vec::new(),
+            assembler: Assembler::default(),
+            outbox: Outbox::default(),
+            seen: HashMap::new(),
+            asked: HashMap::new(),
+            store: Store::open(db_path, keyring.node),
+            keyring,
+            transfers: Transfers::default(),
+            offering: HashMap::new(),
+            disk: mpsc::channel(),
+            downloads,
+            events: Vec::new(),
+        })
     }
 
-    pub fn prelude(&mut self, repaint_signal: Arc<dyn RepaintSignal>) {
-        self.db_create();
-        if let Ok(history) = self.db_get_all() {
+    pub fn prelude(&mut self, wake: Wake) {
+        self.store.migrate();
+        if let Ok(history) = self.store.latest(PAGE) {
+            self.whole_history = history.len() < PAGE;
+            self.first_row = history.iter().filter_map(|m| m.row).min();
             self.history = history;
         };
-        self.connect();
-        self.listen(repaint_signal);
-        self.message = Message::enter(&self.name);
-        self.send(Recepients::All);
-    }
-
-    fn connect(&mut self) {
-        if let Some(local_ip) = local_ipaddress::get() {
-            if let Ok(my_ip) = local_ip.parse::<Ipv4Addr>() {
-                self.ip = my_ip;
-                self.socket = match UdpSocket::bind(format!("{}:{}", self.ip, self.port)) {
-                    Ok(socket) => {
-                        socket.set_broadcast(true).unwrap();
-                        socket.set_multicast_loop_v4(false).unwrap();
-                        Some(Arc::new(socket))
-                    }
-                    _ => None,
-                };
+        self.conversations = self.store.conversations();
+        self.transport.tick(Some(wake.clone()));
+        self.wake = Some(wake);
+        self.connect(network::resolve(&self.interface, self.discovery));
+        self.announce();
+    }
+
+    /// Lets everyone know who we are and where we are.
+    fn announce(&mut self) {
+        self.send(self.enter(true), Recepients::All);
+        self.transport
+            .set_beacon(self.datagrams(&self.enter(false), Recepients::All, &[]));
+        self.beat();
+        for channel in self.channels.clone() {
+            self.send(Message::join_channel(&channel, true), Recepients::All);
+        }
+    }
+
+    /// Events from now on. There can be any number of subscribers.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ChatEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.events.push(tx);
+        rx
+    }
+
+    fn emit(&mut self, event: ChatEvent) {
+        self.events.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Who we are to peers, wherever we chat from.
+    pub fn node(&self) -> NodeId {
+        self.keyring.node
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Our address as peers see it, with brackets around IPv6 ones.
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    pub fn discovery(&self) -> Discovery {
+        self.discovery
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The loaded part of the history, see `load_older`.
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
+    }
+
+    /// Whether a room or direct conversation has older messages on disk.
+    pub fn has_older(&self, direct: Option<NodeId>, channel: &str) -> bool {
+        !self.whole_history && !self.complete.contains(&view(direct, channel))
+    }
+
+    /// Loads a page of a room or direct conversation from before the oldest
+    /// message loaded. Returns whether there was any.
+    pub fn load_older(&mut self, direct: Option<NodeId>, channel: &str) -> bool {
+        if !self.has_older(direct, channel) {
+            return false;
+        }
+        let oldest = self
+            .history
+            .iter()
+            .filter(|m| m.is_in(direct, channel))
+            .filter_map(|m| m.row)
+            .min()
+            .or(self.first_row);
+        let page = match self.store.older(direct, channel, oldest, PAGE) {
+            Ok(page) => page,
+            Err(err) => {
+                warn!("DB! {}", err);
+                Vec::new()
             }
+        };
+        if page.len() < PAGE {
+            self.complete.insert(view(direct, channel));
         }
+        let loaded: HashSet<(NodeId, u64)> = self
+            .history
+            .iter()
+            .filter(|m| m.is_in(direct, channel))
+            .map(|m| (m.node, m.id))
+            .collect();
+        let page: Vec<HistoryEntry> = page
+            .into_iter()
+            .filter(|m| !loaded.contains(&(m.node, m.id)))
+            .collect();
+        let at = self
+            .history
+            .iter()
+            .position(|m| m.is_in(direct, channel))
+            .unwrap_or(self.history.len());
+        let added = !page.is_empty();
+        self.history.splice(at..at, page);
+        added
+    }
+
+    /// Loads pages of its room or conversation until `entry` is among them.
+    pub fn load_back_to(&mut self, entry: &HistoryEntry) {
+        while !self
+            .history
+            .iter()
+            .any(|m| m.node == entry.node && m.id == entry.id)
+        {
+            if !self.load_older(entry.direct, &entry.channel) {
+                return;
+            }
+        }
+    }
+
+    /// Peers of the direct conversations, the earliest one first.
+    pub fn conversations(&self) -> &[NodeId] {
+        &self.conversations
+    }
+
+    pub fn peers(&self) -> &Peers {
+        &self.peers
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    pub fn set_trusted(&mut self, key: [u8; crypto::KEY_LEN], trusted: bool) {
+        self.keyring.set_trusted(key, trusted);
+    }
+
+    pub fn transfers(&self) -> &Transfers {
+        &self.transfers
     }
 
-    fn listen(&self, repaint_signal: Arc<dyn RepaintSignal>) {
-        if let Some(socket) = &self.socket {
-            let reader = Arc::clone(socket);
-            let receiver = self.sync_sender.clone();
-            let repaint_signal = Arc::clone(&repaint_signal);
-            thread::spawn(move || {
-                let mut buf = [0; 2048];
-                let repaint_signal = Arc::clone(&repaint_signal);
-                loop {
-                    if let Ok((number_of_bytes, SocketAddr::V4(src_addr_v4))) =
-                        reader.recv_from(&mut buf)
-                    {
-                        let ip = *src_addr_v4.ip();
-                        if let Some(message) =
-                            Message::from_be_bytes(&buf[..number_of_bytes.min(128)])
-                        {
-                            info!("{}: {}", ip, message);
-                            repaint_signal.request_repaint();
-                            receiver.send((ip, message)).ok();
+    /// How well `receive` keeps up with the network.
+    pub fn inbox_stats(&self) -> InboxStats {
+        self.inbox.stats()
+    }
+
+    pub fn db_status(&self) -> String {
+        self.store.status()
+    }
+
+    pub fn presence(&self) -> Presence {
+        self.presence
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    /// Changes how we show up for others and tells them right away.
+    pub fn set_presence(&mut self, presence: Presence, status: &str) {
+        self.presence = presence;
+        self.status = status.trim().to_string();
+        self.auto_away = false;
+        self.beat();
+    }
+
+    /// The user did something. Brings us back if we went away on our own.
+    pub fn active(&mut self) {
+        self.active = Instant::now();
+        if self.auto_away {
+            info!("Back from away.");
+            self.set_presence(Presence::Online, &self.status.clone());
+        }
+    }
+
+    /// Tells every peer how available we are, sealed so nobody else can.
+    /// Each one is new, so a copy of an older one proves nothing.
+    fn beat(&mut self) {
+        self.beaten = Instant::now();
+        let heartbeat = Message::heartbeat(self.presence, &self.status);
+        self.transmit(&heartbeat, Recepients::Peers);
+    }
+
+    /// Joins a room and returns its normalized name.
+    pub fn join(&mut self, channel: &str) -> Option<String> {
+        let channel = normalize_channel(channel)?;
+        if channel != DEFAULT_CHANNEL && !self.channels.contains(&channel) {
+            info!("Joining #{}", channel);
+            self.channels.push(channel.to_owned());
+            self.send(Message::join_channel(&channel, true), Recepients::All);
+        }
+        Some(channel)
+    }
+
+    pub fn leave(&mut self, channel: &str) {
+        if let Some(i) = self.channels.iter().position(|c| c == channel) {
+            info!("Leaving #{}", channel);
+            self.channels.remove(i);
+            self.send(Message::leave_channel(channel), Recepients::Peers);
+        }
+    }
+
+    fn connect(&mut self, links: Vec<Link>) {
+        self.links = links;
+        let state = match self.links.first() {
+            Some(link) => {
+                self.ip = link.ip;
+                let started =
+                    network::bind(&self.links, self.port, self.discovery).and_then(|sockets| {
+                        let port = sockets.own.local_addr()?.port();
+                        let wake = self.wake.clone().unwrap_or_else(|| Arc::new(|| ()));
+                        self.transport.start(
+                            sockets,
+                            self.inbox.postman(),
+                            wake,
+                            Arc::clone(&self.broken),
+                        )?;
+                        Ok(port)
+                    });
+                match started {
+                    Ok(port) => {
+                        for link in &self.links {
+                            info!("{}: discovery via {}", link.ip, link.everyone);
                         }
+                        info!("Node {} answers on port {}", self.keyring.node, port);
+                        ConnectionState::Online(self.local_addr())
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                        ConnectionState::PortInUse(self.port)
                     }
+                    Err(err) => ConnectionState::Failed(err.to_string()),
                 }
-            });
+            }
+            None => ConnectionState::NoInterface(self.interface.to_owned()),
+        };
+        self.set_connection(state);
+    }
+
+    fn set_connection(&mut self, state: ConnectionState) {
+        if state != self.connection {
+            match state.is_online() {
+                true => info!("Connection: {}", state),
+                false => warn!("Connection! {}", state),
+            }
+            self.connection = state.to_owned();
+            self.emit(ChatEvent::Connection(state));
         }
     }
 
-    pub fn send(&mut self, mut addrs: Recepients) {
-        match self.message.command {
+    pub fn connection(&self) -> &ConnectionState {
+        &self.connection
+    }
+
+    /// Tries to get online again right away instead of waiting for the next check.
+    pub fn reconnect(&mut self) {
+        if self.wake.is_some() {
+            self.checked = Instant::now();
+            self.rebind(network::resolve(&self.interface, self.discovery));
+        }
+    }
+
+    /// Moves to wherever the chosen interface is now.
+    fn rebind(&mut self, links: Vec<Link>) {
+        self.transport.stop();
+        self.connect(links);
+        self.announce();
+    }
+
+    pub fn interface(&self) -> &Interface {
+        &self.interface
+    }
+
+    /// Chooses another interface, moving there right away if started.
+    pub fn set_interface(&mut self, interface: Interface) {
+        self.interface = interface;
+        if self.wake.is_some() {
+            self.rebind(network::resolve(&self.interface, self.discovery));
+        }
+    }
+
+    /// Says goodbye and stops the network tasks. Does nothing if not started.
+    pub fn shutdown(&mut self) {
+        if self.wake.take().is_none() {
+            return;
+        }
+        for _ in 0..EXIT_REPEATS {
+            self.transmit(&Message::exit(), Recepients::Peers);
+        }
+        self.transport.tick(None);
+        self.transport.stop();
+        self.set_connection(ConnectionState::Stopped);
+        info!("Chat stopped.");
+    }
+
+    pub fn send(&mut self, message: Message, addrs: Recepients) {
+        match message.command {
             Command::Empty => return,
-            Command::Text => {
-                self.db_save(self.ip, &self.message.clone());
+            Command::Text | Command::Offer => {
+                self.active();
+                let direct = match (&addrs, message.private) {
+                    (Recepients::One(node), true) => Some(*node),
+                    _ => None,
+                };
+                self.seen.insert((self.node(), message.id), Instant::now());
+                // Only those who can take files are waited for with an offer.
+                let recipients = match message.command {
+                    Command::Offer => self.file_recipients(&addrs, &message.channel),
+                    _ => self.recipients(&addrs, &message.channel),
+                };
+                let delivery = self.outbox.push(message.clone(), recipients);
+                self.record(HistoryEntry {
+                    id: message.id,
+                    node: self.node(),
+                    name: self.name.to_owned(),
+                    timestamp: message.timestamp,
+                    text: text_of(&message),
+                    channel: message.channel.to_owned(),
+                    direct,
+                    delivery: Some(delivery),
+                    file: (message.command == Command::Offer).then_some(message.id),
+                    row: None,
+                });
             }
             _ => (),
         }
+        self.transmit(&message, addrs);
+    }
 
-        let bytes = self.message.to_be_bytes();
-        if let Some(socket) = &self.socket {
-            if self.peers.len() == 1 {
-                addrs = Recepients::All;
+    /// Offers a file to a peer or to everyone in `channel`, once it is hashed.
+    /// Errors found by then come as `ChatEvent::OfferFailed`.
+    pub fn send_file(&mut self, path: &Path, addrs: Recepients, channel: &str) -> io::Result<()> {
+        if let Recepients::One(node) = addrs {
+            if !self.peers.supports(&node, Capabilities::FILES) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the peer can't receive files",
+                ));
             }
-            let recepients: Vec<String> = match addrs {
-                Recepients::All => (0..=254)
-                    .map(|i| {
-                        format!(
-                            "{}.{}.{}.{}:{}",
-                            self.ip.octets()[0],
-                            self.ip.octets()[1],
-                            self.ip.octets()[2],
-                            i,
-                            self.port
-                        )
-                    })
-                    .collect(),
-                Recepients::Peers => self
-                    .peers
-                    .iter()
-                    .map(|ip| format!("{}:{}", ip, self.port))
-                    .collect(),
-                Recepients::One(ip) => vec![format!("{}:{}", ip, self.port)],
-            };
-            for recepient in recepients {
-                socket.send_to(&bytes, recepient).ok();
+        }
+        let id = self.transfers.prepare(path)?;
+        self.offering.insert(id, (addrs, channel.to_owned()));
+        self.run_jobs();
+        Ok(())
+    }
+
+    /// Offers a file that was just hashed.
+    fn offer(
+        &mut self,
+        id: u64,
+        path: PathBuf,
+        hashed: io::Result<(u64, [u8; transfer::HASH_LEN])>,
+    ) {
+        let (addrs, channel) = match self.offering.remove(&id) {
+            Some(offering) => offering,
+            None => return,
+        };
+        let hashed = match hashed {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                warn!("{}! {}", path.display(), err);
+                self.emit(ChatEvent::OfferFailed {
+                    path,
+                    error: err.to_string(),
+                });
+                return;
+            }
+        };
+        let recipients = self.file_recipients(&addrs, &channel);
+        let mut offer = self
+            .transfers
+            .offer(id, self.node(), &path, hashed, recipients)
+            .in_channel(&channel);
+        offer.private = matches!(addrs, Recepients::One(_));
+        self.send(offer, addrs);
+    }
+
+    /// Runs slow work of the front-end off its thread, waking it when done.
+    pub fn blocking(&self, work: impl FnOnce() + Send + 'static) {
+        let wake = self.wake.clone();
+        self.transport.blocking(move || {
+            work();
+            if let Some(wake) = wake {
+                wake();
             }
+        });
+    }
+
+    /// Hands the disk work of transfers to the transport.
+    fn run_jobs(&mut self) {
+        for job in self.transfers.jobs() {
+            let done = self.disk.0.clone();
+            let wake = self.wake.clone();
+            self.transport.blocking(move || {
+                done.send(job.run()).ok();
+                if let Some(wake) = wake {
+                    wake();
+                }
+            });
         }
-        // self.message = Message::empty();
+    }
+
+    /// Accepts a download, or continues a paused or failed one.
+    pub fn resume_transfer(&mut self, id: u64) {
+        self.transfers.resume(id);
+    }
+
+    pub fn pause_transfer(&mut self, id: u64) {
+        self.transfers.pause(id);
+    }
+
+    /// Stops a transfer on both sides.
+    pub fn cancel_transfer(&mut self, id: u64) {
+        for node in self.transfers.cancel(id) {
+            self.transmit(&Message::cancel(id), Recepients::One(node));
+        }
+    }
+
+    /// Peers expected to acknowledge what we send to `addrs`.
+    fn recipients(&self, addrs: &Recepients, channel: &str) -> HashSet<NodeId> {
+        match addrs {
+            Recepients::One(node) => HashSet::from([*node]),
+            _ => self
+                .peers
+                .in_channel(channel)
+                .filter(|node| node != &self.node())
+                .collect(),
+        }
+    }
+
+    /// Those of `recipients` that can receive files.
+    fn file_recipients(&self, addrs: &Recepients, channel: &str) -> HashSet<NodeId> {
+        let mut recipients = self.recipients(addrs, channel);
+        recipients.retain(|node| self.peers.supports(node, Capabilities::FILES));
+        recipients
+    }
+
+    fn transmit(&self, message: &Message, addrs: Recepients) {
+        self.transmit_parts(message, addrs, &[]);
+    }
+
+    /// Sends `message` split into parts, only the `wanted` ones if any are given.
+    fn transmit_parts(&self, message: &Message, addrs: Recepients, wanted: &[u16]) {
+        for datagram in self.datagrams(message, addrs, wanted) {
+            self.transport.send(datagram);
+        }
+    }
+
+    /// What goes on the wire for `message`. Sealed commands are sealed for
+    /// each recipient and skip those we share no key with.
+    fn datagrams(&self, message: &Message, mut addrs: Recepients, wanted: &[u16]) -> Vec<Datagram> {
+        if !self.connection.is_online() {
+            return vec![];
+        }
+        if matches!(addrs, Recepients::Peers) && self.peers.iter().all(|(n, _)| *n == self.node()) {
+            addrs = Recepients::All;
+        }
+        let peer = |node: NodeId| {
+            let addr = network::reply_addr(self.peers.addr(&node)?, self.ip);
+            Some((Some(node), addr, None))
+        };
+        let recepients: Vec<(Option<NodeId>, SocketAddr, Option<Link>)> = match addrs {
+            Recepients::All => self
+                .links
+                .iter()
+                .map(|link| {
+                    let addr = network::socket_addr(link.everyone, self.port, self.ip, link.scope);
+                    (None, addr, Some(*link))
+                })
+                .collect(),
+            Recepients::Peers => self
+                .peers
+                .in_channel(&message.channel)
+                .filter_map(peer)
+                .collect(),
+            Recepients::One(node) => peer(node).into_iter().collect(),
+        };
+        self.seal_for(message, recepients, wanted)
+    }
+
+    /// Sends `message` to `node` at `addr`, wherever we thought it was.
+    fn reply(&self, message: &Message, node: NodeId, addr: SocketAddr) {
+        if !self.connection.is_online() {
+            return;
+        }
+        let to = network::reply_addr(addr, self.ip);
+        for datagram in self.seal_for(message, vec![(Some(node), to, None)], &[]) {
+            self.transport.send(datagram);
+        }
+    }
+
+    /// Datagrams of `message` for each recipient, sealed if it has to be.
+    fn seal_for(
+        &self,
+        message: &Message,
+        recepients: Vec<(Option<NodeId>, SocketAddr, Option<Link>)>,
+        wanted: &[u16],
+    ) -> Vec<Datagram> {
+        let mut datagrams = Vec::new();
+        for (node, addr, link) in recepients {
+            let mut message = match message.command.is_sealed() {
+                true => match node.and_then(|node| self.keyring.seal(&node, message)) {
+                    Some(sealed) => sealed,
+                    None => continue,
+                },
+                false => message.clone(),
+            };
+            message.node = self.node();
+            datagrams.extend(
+                message
+                    .split()
+                    .into_iter()
+                    .filter(|part| wanted.is_empty() || wanted.contains(&part.part))
+                    .map(|part| Datagram {
+                        to: addr,
+                        bytes: part.to_be_bytes(),
+                        via: link,
+                    }),
+            );
+        }
+        datagrams
+    }
+
+    fn enter(&self, reply: bool) -> Message {
+        Message::enter(&self.name, self.keyring.public.as_bytes(), reply)
     }
 
     pub fn receive(&mut self) {
-        if let Ok(message) = self.sync_receiver.try_recv() {
-            match message.1.command {
-                Command::Enter => {
-                    info!("{} entered chat.", message.0);
-                    if !self.peers.contains(&message.0) {
-                        self.peers.insert(message.0);
-                        if message.0 != self.ip {
-                            self.message = Message::enter(&self.name);
-                            self.send(Recepients::One(message.0));
-                        }
+        self.asked
+            .retain(|_, asked| asked.elapsed() < REPEAT_WINDOW);
+        self.seen.retain(|_, seen| seen.elapsed() < SEEN_FOR);
+        if self.beaten.elapsed() >= HEARTBEAT {
+            self.beat();
+        }
+        for (node, id, parts) in self.assembler.stalled() {
+            info!("{}: message #{} misses parts {:?}", node, id, parts);
+            self.ask_to_repeat(node, id, &parts);
+        }
+        let (retries, failed) = self.outbox.due();
+        for (message, nodes) in retries {
+            info!("Repeating #{:016x} to {:?}", message.id, nodes);
+            for node in nodes {
+                self.transmit(&message, Recepients::One(node));
+            }
+        }
+        for id in failed {
+            warn!("#{:016x} was not delivered.", id);
+            self.set_delivery(id, Delivery::Failed);
+        }
+        while let Ok(done) = self.disk.1.try_recv() {
+            match done {
+                Done::Hashed { id, path, result } => self.offer(id, path, result),
+                done => {
+                    for (node, message) in self.transfers.done(done) {
+                        self.transmit(&message, Recepients::One(node));
                     }
                 }
-                Command::Text | Command::Repeat => {
-                    if message.0 != self.ip {
-                        self.db_save(message.0, &message.1);
+            }
+        }
+        for (node, id, chunks) in self.transfers.due() {
+            self.transmit(&Message::request(id, &chunks), Recepients::One(node));
+        }
+        for (id, state) in self.transfers.changes() {
+            self.emit(ChatEvent::Transfer { id, state });
+        }
+        if self.presence == Presence::Online && self.active.elapsed() >= AWAY_AFTER {
+            info!("Idle, going away.");
+            self.set_presence(Presence::Away, &self.status.clone());
+            self.auto_away = true;
+        }
+        if self.broken.swap(false, Ordering::Relaxed) && self.connection.is_online() {
+            self.set_connection(ConnectionState::Reconnecting);
+        }
+        if self.wake.is_some() && self.checked.elapsed() >= NETWORK_CHECK {
+            self.checked = Instant::now();
+            let links = network::resolve(&self.interface, self.discovery);
+            if links != self.links || !self.connection.is_online() {
+                self.rebind(links);
+            }
+        }
+        let own = self.node();
+        for node in self.peers.expire(PEER_TIMEOUT) {
+            if node == own {
+                continue;
+            }
+            info!("{} went silent.", node);
+            self.keyring.forget(&node);
+            self.emit(ChatEvent::PeerLeft { node });
+        }
+        for (addr, message) in self.inbox.drain() {
+            self.handle(addr, message);
+        }
+        self.run_jobs();
+    }
+
+    /// Reacts to a message from the network.
+    fn handle(&mut self, addr: SocketAddr, message: Message) {
+        let node = message.node;
+        if message.command == Command::Repeat && !self.asked.contains_key(&(node, message.id)) {
+            warn!("{}: unasked Repeat #{:016x}", node, message.id);
+            return;
+        }
+        let message = match message.command {
+            Command::Text | Command::Repeat => match self.assembler.insert(node, message) {
+                Some(message) => {
+                    self.asked.remove(&(node, message.id));
+                    message
+                }
+                None => return,
+            },
+            _ => message,
+        };
+        let message = match message.command.is_sealed() {
+            true => match self.keyring.open(&node, &message) {
+                Some(message) => message,
+                // Another copy, after we forgot the key with the first one.
+                None if message.command == Command::Exit => return,
+                None => {
+                    // Most likely we missed their Enter: ask for it.
+                    warn!("{}: can't open #{:016x}", node, message.id);
+                    self.peers.seen(node, addr);
+                    self.reply(&self.enter(true), node, addr);
+                    return;
+                }
+            },
+            false => message,
+        };
+        match message.command {
+            Command::Enter => {
+                if let Some((reply, key, name)) = message.read_enter() {
+                    if !self.keyring.handshake(node, key) {
+                        warn!("{}: Enter from {} with someone else's key", node, addr);
+                        self.emit(ChatEvent::KeyRefused { node });
+                        return;
+                    }
+                    let is_new = self.peers.enter(node, addr, &name);
+                    if is_new {
+                        info!("{} entered chat as '{}' from {}.", node, name, addr);
+                        self.peers.set_capabilities(node, message.capabilities);
                     }
-                    let text = message.1.read_text();
-                    self.history.push((message.0, text));
-                    if !self.peers.contains(&message.0) {
-                        self.peers.insert(message.0);
-                        if message.0 != self.ip {
-                            self.message = Message::enter(&self.name);
-                            self.send(Recepients::One(message.0));
+                    if node != self.node() {
+                        if is_new {
+                            self.emit(ChatEvent::PeerEntered { node, name });
+                        }
+                        // Answered where it came from: a peer we know may
+                        // have restarted elsewhere, and what is sealed only
+                        // the peer can open.
+                        if reply || is_new {
+                            self.reply(&self.enter(false), node, addr);
+                            self.reply(&Message::name(&self.name), node, addr);
+                            self.reply(
+                                &Message::heartbeat(self.presence, &self.status),
+                                node,
+                                addr,
+                            );
+                            for channel in self.channels.clone() {
+                                self.reply(&Message::join_channel(&channel, false), node, addr);
+                            }
                         }
                     }
                 }
-                Command::Damaged => {
-                    self.message =
-                        Message::new(Command::AskToRepeat, message.1.id.to_be_bytes().to_vec());
-                    self.send(Recepients::One(message.0));
+            }
+            Command::Text | Command::Repeat | Command::Offer | Command::Chunk => {
+                if message.command == Command::Chunk {
+                    if let Some((id, index, bytes)) = message.read_chunk() {
+                        self.transfers.chunk(node, id, index, bytes);
+                    }
+                    return;
                 }
-                Command::AskToRepeat => {
-                    let id: u32 = u32::from_be_bytes(
-                        (0..4)
-                            .map(|i| *message.1.data.get(i).unwrap_or(&0))
-                            .collect::<Vec<u8>>()
-                            .try_into()
-                            .unwrap(),
-                    );
-                    self.message = Message::retry_text(
-                        id,
-                        &self
-                            .db_get_by_id(id)
-                            .unwrap_or_else(|| String::from("NO SUCH MESSAGE! = (")),
+                self.transmit(&Message::ack(message.id), Recepients::One(node));
+                // Copies come soon after, unless someone kept one for later.
+                if self
+                    .seen
+                    .insert((node, message.id), Instant::now())
+                    .is_some()
+                    || self.store.contains(node, message.id)
+                {
+                    return;
+                }
+                if message.channel != DEFAULT_CHANNEL && !self.channels.contains(&message.channel) {
+                    return;
+                }
+                if message.command == Command::Offer
+                    && !self.transfers.offered(node, &message, &self.downloads)
+                {
+                    return;
+                }
+                let is_new = self.peers.seen(node, addr);
+                let name = self.peer_name(&node);
+                let direct = message.private.then_some(node);
+                self.record(HistoryEntry {
+                    id: message.id,
+                    node,
+                    name,
+                    timestamp: message.timestamp,
+                    text: text_of(&message),
+                    channel: message.channel.to_owned(),
+                    direct,
+                    delivery: None,
+                    file: (message.command == Command::Offer).then_some(message.id),
+                    row: None,
+                });
+                if is_new {
+                    self.send(self.enter(true), Recepients::One(node));
+                }
+            }
+            Command::Damaged => {
+                let parts = match message.parts {
+                    1 => vec![],
+                    _ => vec![message.part],
+                };
+                self.ask_to_repeat(node, message.id, &parts);
+            }
+            Command::AskToRepeat => {
+                let (id, wanted) = message.read_repeat_request();
+                if let Some(offer) = self.transfers.offer_for(&node, &id) {
+                    self.transmit_parts(&offer.clone(), Recepients::One(node), &wanted);
+                    return;
+                }
+                // A made-up answer would reuse the nonce of the original text.
+                match self.store.own_message(id) {
+                    // Direct messages are only repeated to whom they were sent.
+                    Some((message, direct)) if direct.unwrap_or(node) == node => {
+                        self.transmit_parts(&message, Recepients::One(node), &wanted)
+                    }
+                    _ => warn!("{} asked for unknown #{:016x}", node, id),
+                }
+            }
+            Command::Ack if self.outbox.ack(node, message.read_id()) => {
+                self.set_delivery(message.read_id(), Delivery::Delivered);
+            }
+            Command::Join => {
+                self.peers.join(node, addr, &message.channel);
+                self.emit(ChatEvent::Joined {
+                    node,
+                    channel: message.channel.to_owned(),
+                });
+                if message.wants_reply()
+                    && node != self.node()
+                    && self.channels.contains(&message.channel)
+                {
+                    self.send(
+                        Message::join_channel(&message.channel, false),
+                        Recepients::One(node),
                     );
-                    self.send(Recepients::One(message.0));
                 }
-                Command::Exit => {
-                    info!("{} left chat.", message.0);
-                    self.peers.remove(&message.0);
+            }
+            Command::Leave => {
+                self.peers.leave(&node, &message.channel);
+                self.emit(ChatEvent::Left {
+                    node,
+                    channel: message.channel.to_owned(),
+                });
+            }
+            Command::Request => {
+                let (id, chunks) = message.read_request();
+                self.transfers.serve(node, id, &chunks);
+            }
+            Command::Cancel => self.transfers.cancelled(node, message.read_id()),
+            Command::Heartbeat if self.peers.proven(node, addr, message.timestamp, message.id) => {
+                let (presence, status) = message.read_heartbeat();
+                if self.peers.presence(node, presence, &status) {
+                    self.emit(ChatEvent::Presence {
+                        node,
+                        presence,
+                        status,
+                    });
                 }
-                _ => (),
             }
+            // Only new ones say where the peer is now: a copy could come
+            // from anywhere.
+            Command::Name if self.peers.proven(node, addr, message.timestamp, message.id) => {
+                let name = message.read_name();
+                self.peers.set_capabilities(node, message.capabilities);
+                if self.peers.rename(node, &name) {
+                    info!("{} is now '{}'.", node, name);
+                }
+            }
+            Command::Exit => {
+                info!("{} left chat.", node);
+                self.peers.remove(&node);
+                self.keyring.forget(&node);
+                self.emit(ChatEvent::PeerLeft { node });
+            }
+            _ => (),
         }
     }
 
-    fn db_create(&mut self) {
-        if let Some(db) = &self.db {
-            self.db_status = match db.execute(
-                "create table if not exists chat_history (
-                id integer primary key,
-                ip text not null,
-                message_text text not null
-                )",
-                [],
-            ) {
-                Ok(_) => "DB is ready.".to_string(),
-                Err(err) => format!("DB Err: {}", err),
-            };
-            warn!("{}", self.db_status);
+    /// Asks `node` for missing parts of message `id`, or all of it without `parts`.
+    fn ask_to_repeat(&mut self, node: NodeId, id: u64, parts: &[u16]) {
+        self.asked.insert((node, id), Instant::now());
+        self.send(Message::ask_to_repeat(id, parts), Recepients::One(node));
+    }
+
+    /// Keeps a text or an offer, ours or received.
+    fn record(&mut self, entry: HistoryEntry) {
+        self.store.save(&entry);
+        self.emit(ChatEvent::Message(entry.clone()));
+        if let Some(node) = entry.direct {
+            if !self.conversations.contains(&node) {
+                self.conversations.push(node);
+            }
         }
+        self.history.push(entry);
     }
-    fn db_save(&mut self, ip: Ipv4Addr, message: &Message) {
-        if let Some(db) = &self.db {
-            self.db_status = match db.execute(
-                "INSERT INTO chat_history (id, ip, message_text) values (?1, ?2, ?3)",
-                [message.id.to_string(), ip.to_string(), message.read_text()],
-            ) {
-                Ok(_) => "DB: appended.".to_string(),
-                Err(err) => format!("DB! {}", err),
-            };
-            info!("{}", self.db_status);
+
+    fn set_delivery(&mut self, id: u64, delivery: Delivery) {
+        let node = self.node();
+        if let Some(entry) = self
+            .history
+            .iter_mut()
+            .rev()
+            .find(|e| e.id == id && e.node == node)
+        {
+            entry.delivery = Some(delivery);
         }
+        self.store.set_delivery(id, delivery);
+        self.emit(ChatEvent::Delivery { id, delivery });
     }
-    fn db_get_all(&mut self) -> rusqlite::Result<Vec<(Ipv4Addr, String)>> {
-        if let Some(db) = &self.db {
-            let mut stmt = db.prepare("SELECT ip, message_text FROM chat_history")?;
-            let mut rows = stmt.query([])?;
-            let mut story = Vec::<(String, String)>::new();
-            while let Some(row) = rows.next()? {
-                story.push((row.get(0)?, row.get(1)?));
-            }
 
-            Ok(story
-                .iter()
-                .map(|row| (row.0.parse::<Ipv4Addr>().unwrap(), row.1.to_owned()))
-                .collect())
-        } else {
-            Ok(Vec::<(Ipv4Addr, String)>::new())
-        }
-    }
-    fn db_get_by_id(&mut self, id: u32) -> Option<String> {
-        if let Some(db) = &self.db {
-            match db.query_row(
-                "SELECT message_text FROM chat_history WHERE id = ?",
-                [id],
-                |row| row.get(0),
-            ) {
-                Ok(message_text) => message_text,
-                Err(_) => None,
-            }
-        } else {
-            None
+    /// Current nickname of a peer, or where it is if it hasn't told us yet.
+    pub fn peer_name(&self, node: &NodeId) -> String {
+        if node == &self.node() {
+            return self.name.to_owned();
         }
+        match (self.peers.name(node), self.peers.addr(node)) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => node.to_string(),
+        }
+    }
+
+    /// Messages on disk that match `query`, newest first.
+    pub fn search(&self, query: &Query) -> rusqlite::Result<Vec<Hit>> {
+        self.store.search(query)
+    }
+
+    /// Senders a search can be narrowed to.
+    pub fn search_senders(&self) -> Vec<(NodeId, String)> {
+        self.store.senders()
     }
+
+    /// Rooms a search can be narrowed to.
+    pub fn search_channels(&self) -> Vec<String> {
+        self.store.channels()
+    }
+
     pub fn clear_history(&mut self) {
-        if let Some(db) = &self.db {
-            if let Some(db_path) = db.path() {
-                self.db_status = match std::fs::remove_file(db_path) {
-                    Ok(_) => "DB: Cleared".to_string(),
-                    Err(err) => format!("DB! {}", err),
-                };
-                info!("{}", self.db_status);
-                self.db_create();
-            }
+        self.store.clear();
+        self.history = Vec::<HistoryEntry>::new();
+        self.whole_history = true;
+        self.complete.clear();
+        self.conversations.clear();
+    }
+}
+
+/// Key of a room, or of a direct conversation whatever its channel.
+fn view(direct: Option<NodeId>, channel: &str) -> (Option<NodeId>, String) {
+    match direct {
+        Some(_) => (direct, String::new()),
+        None => (None, channel.to_string()),
+    }
+}
+
+/// How a text or a file offer reads in the history.
+fn text_of(message: &Message) -> String {
+    match message.read_offer() {
+        Some((size, _, name)) if message.command == Command::Offer => {
+            format!("📎 {} ({})", name, size_label(size))
         }
-        self.history = Vec::<(Ipv4Addr, String)>::new();
+        _ => message.read_text(),
+    }
+}
+
+impl Drop for UdpChat {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
@@ -0,0 +1,402 @@
+use if_addrs::{get_if_addrs, IfAddr};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::str::FromStr;
+
+/// Room for bursts while the network tasks wait for the front-end.
+const RECV_BUFFER: usize = 1 << 20;
+
+/// Administratively scoped group, never routed off the local network.
+pub const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 44, 44);
+/// Link-local group for IPv6, which has no broadcast.
+pub const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4444, 0x4444);
+
+/// How `Recepients::All` reaches peers that we don't know yet.
+/// Ignored on IPv6, which always uses `MULTICAST_GROUP_V6`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Discovery {
+    #[default]
+    Broadcast,
+    Multicast,
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discovery::Broadcast => write!(f, "broadcast"),
+            Discovery::Multicast => write!(f, "multicast"),
+        }
+    }
+}
+
+impl FromStr for Discovery {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "broadcast" => Ok(Discovery::Broadcast),
+            "multicast" => Ok(Discovery::Multicast),
+            _ => Err(format!("Unknown discovery '{}'", s)),
+        }
+    }
+}
+
+/// Where the chat stands with the network.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ConnectionState {
+    /// Not started yet.
+    Binding,
+    Online(SocketAddr),
+    /// Someone else holds the port.
+    PortInUse(u16),
+    /// The chosen interface is missing or has no address.
+    NoInterface(Interface),
+    /// The network broke while we were online.
+    Reconnecting,
+    /// Binding failed for another reason.
+    Failed(String),
+    /// Shut down.
+    Stopped,
+}
+
+impl ConnectionState {
+    pub fn is_online(&self) -> bool {
+        matches!(self, ConnectionState::Online(_))
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Binding => write!(f, "Connecting…"),
+            ConnectionState::Online(addr) => write!(f, "{}", addr),
+            ConnectionState::PortInUse(port) => write!(f, "Port {} is in use!", port),
+            ConnectionState::NoInterface(interface) => {
+                write!(f, "No network on '{}'!", interface)
+            }
+            ConnectionState::Reconnecting => write!(f, "Reconnecting…"),
+            ConnectionState::Failed(err) => write!(f, "Offline! {}", err),
+            ConnectionState::Stopped => write!(f, "Offline."),
+        }
+    }
+}
+
+/// Whether a socket error means the network under us is gone.
+pub fn is_network_down(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NetworkDown
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable
+    )
+}
+
+/// Directed broadcast address of the subnet `ip` belongs to.
+pub fn subnet_broadcast(ip: Ipv4Addr) -> Option<Ipv4Addr> {
+    get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find_map(|iface| match iface.addr {
+            IfAddr::V4(v4) if v4.ip == ip => Some(
+                v4.broadcast
+                    .unwrap_or_else(|| Ipv4Addr::from(u32::from(v4.ip) | !u32::from(v4.netmask))),
+            ),
+            _ => None,
+        })
+}
+
+/// Which network to chat on.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Interface {
+    /// Whatever the system routes through by default.
+    #[default]
+    Auto,
+    /// Every interface of the same IP family as the default one.
+    All,
+    /// An interface by name, wherever its address moves.
+    Name(String),
+    /// A fixed local address.
+    Ip(IpAddr),
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interface::Auto => write!(f, "auto"),
+            Interface::All => write!(f, "all"),
+            Interface::Name(name) => write!(f, "{}", name),
+            Interface::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl FromStr for Interface {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" | "auto" => Ok(Interface::Auto),
+            "all" => Ok(Interface::All),
+            s => match s.parse::<IpAddr>() {
+                Ok(ip) => Ok(Interface::Ip(ip)),
+                Err(_) if s.chars().any(char::is_whitespace) => {
+                    Err(format!("Bad interface '{}'", s))
+                }
+                Err(_) => Ok(Interface::Name(s.to_string())),
+            },
+        }
+    }
+}
+
+impl From<Interface> for String {
+    fn from(interface: Interface) -> Self {
+        interface.to_string()
+    }
+}
+
+impl TryFrom<String> for Interface {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A local interface with one of its addresses.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NetInterface {
+    pub name: String,
+    pub ip: IpAddr,
+    /// Index that scopes IPv6 link-local addresses.
+    pub index: u32,
+}
+
+/// Local interfaces, IPv4 addresses first and loopback last.
+/// Loopback is only good for instances on this host, e.g. for testing offline.
+pub fn interfaces() -> Vec<NetInterface> {
+    let mut list: Vec<NetInterface> = get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|iface| NetInterface {
+            ip: iface.ip(),
+            index: iface.index.unwrap_or(0),
+            name: iface.name,
+        })
+        .collect();
+    list.sort_by_key(|iface| {
+        (
+            iface.ip.is_loopback(),
+            iface.ip.is_ipv6(),
+            iface.name.to_owned(),
+        )
+    });
+    list
+}
+
+/// One local address we chat from.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Link {
+    pub ip: IpAddr,
+    /// Interface index, for IPv6 link-local addresses.
+    pub scope: u32,
+    /// Where `Recepients::All` goes from here.
+    pub everyone: IpAddr,
+}
+
+/// Local addresses for `interface`, the first one being the address we go by.
+/// Without a preference it is the first IPv4 address, or an IPv6 one on
+/// IPv6-only networks. Empty when the interface is gone.
+pub fn resolve(interface: &Interface, discovery: Discovery) -> Vec<Link> {
+    let ifaces = interfaces();
+    let default_ip = || {
+        local_ipaddress::get()
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .or_else(|| ifaces.first().map(|iface| iface.ip))
+    };
+    let link = |ip: IpAddr| Link {
+        ip,
+        scope: ifaces
+            .iter()
+            .find(|iface| iface.ip == ip)
+            .map_or(0, |iface| iface.index),
+        everyone: everyone(ip, discovery),
+    };
+    let ips: Vec<IpAddr> = match interface {
+        Interface::Auto => default_ip().into_iter().collect(),
+        Interface::All => match default_ip() {
+            Some(first) => std::iter::once(first)
+                .chain(
+                    ifaces
+                        .iter()
+                        .map(|iface| iface.ip)
+                        .filter(|ip| ip.is_ipv6() == first.is_ipv6() && *ip != first)
+                        .filter(|ip| !ip.is_loopback() || first.is_loopback()),
+                )
+                .collect(),
+            None => Vec::new(),
+        },
+        Interface::Name(name) => {
+            let mut ips = ifaces
+                .iter()
+                .filter(|iface| &iface.name == name)
+                .map(|iface| iface.ip);
+            match ips.next() {
+                // One socket speaks one family.
+                Some(first) => std::iter::once(first)
+                    .chain(ips.filter(|ip| ip.is_ipv6() == first.is_ipv6()))
+                    .collect(),
+                None => Vec::new(),
+            }
+        }
+        // Trust it if the system won't tell us about interfaces.
+        Interface::Ip(ip) if ifaces.is_empty() || ifaces.iter().any(|iface| iface.ip == *ip) => {
+            vec![*ip]
+        }
+        Interface::Ip(_) => Vec::new(),
+    };
+    // Addresses sharing an interface share the IPv6 group too.
+    let mut reached = HashSet::new();
+    ips.into_iter()
+        .map(link)
+        .filter(|link| reached.insert((link.scope, link.everyone)))
+        .collect()
+}
+
+/// All addresses of this host, which our messages in histories saved before
+/// node ids are known by.
+pub fn own_ips() -> HashSet<IpAddr> {
+    get_if_addrs()
+        .map(|ifaces| ifaces.into_iter().map(|iface| iface.ip()).collect())
+        .unwrap_or_default()
+}
+
+/// Where `Recepients::All` goes from `ip`.
+pub fn everyone(ip: IpAddr, discovery: Discovery) -> IpAddr {
+    match (ip, discovery) {
+        (IpAddr::V4(ip), Discovery::Broadcast) => {
+            IpAddr::V4(subnet_broadcast(ip).unwrap_or(Ipv4Addr::BROADCAST))
+        }
+        (IpAddr::V4(_), Discovery::Multicast) => IpAddr::V4(MULTICAST_GROUP),
+        (IpAddr::V6(_), _) => IpAddr::V6(MULTICAST_GROUP_V6),
+    }
+}
+
+/// Destination for `ip` on a socket bound for `local`. IPv4 peers are mapped
+/// on dual-stack sockets, link-local IPv6 ones get the interface `scope`.
+pub fn socket_addr(ip: IpAddr, port: u16, local: IpAddr, scope: u32) -> SocketAddr {
+    match (ip, local) {
+        (IpAddr::V4(ip), IpAddr::V6(_)) => {
+            SocketAddr::V6(SocketAddrV6::new(ip.to_ipv6_mapped(), port, 0, 0))
+        }
+        (IpAddr::V6(ip), _) if is_link_local(&ip) => {
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope))
+        }
+        _ => SocketAddr::new(ip, port),
+    }
+}
+
+/// Where a datagram came from, with IPv4 peers unmapped from dual-stack sockets.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+/// Destination for a peer last seen at `addr`, on a socket bound for `local`.
+pub fn reply_addr(addr: SocketAddr, local: IpAddr) -> SocketAddr {
+    let scope = match addr {
+        SocketAddr::V6(v6) => v6.scope_id(),
+        _ => 0,
+    };
+    socket_addr(addr.ip(), addr.port(), local, scope)
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.is_unicast_link_local() || (ip.is_multicast() && ip.segments()[0] & 0x000f == 2)
+}
+
+/// The chat sockets. Instances on one host share the discovery port, so
+/// each of them also has a port of its own to be answered on.
+pub struct Sockets {
+    /// The configured port, where broadcast and multicast arrive.
+    pub shared: UdpSocket,
+    /// Everything is sent from here, so peers learn where to reply.
+    pub own: UdpSocket,
+}
+
+/// Binds the chat sockets for `links`, which are all of one IP family.
+pub fn bind(links: &[Link], port: u16, discovery: Discovery) -> io::Result<Sockets> {
+    let domain = match links.first().map(|link| link.ip) {
+        Some(IpAddr::V4(_)) => Domain::IPV4,
+        Some(IpAddr::V6(_)) => Domain::IPV6,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "No interface.")),
+    };
+    let shared = socket(domain, port, true)?;
+    let own = socket(domain, 0, false)?;
+    if domain == Domain::IPV4 {
+        own.set_broadcast(true)?;
+        if discovery == Discovery::Multicast {
+            for link in links {
+                if let IpAddr::V4(ip) = link.ip {
+                    shared.join_multicast_v4(&MULTICAST_GROUP, &ip)?;
+                }
+            }
+            own.set_multicast_ttl_v4(1)?;
+            // Our own Enter has to come back to us, like it does with broadcast.
+            own.set_multicast_loop_v4(true)?;
+        }
+    } else {
+        let mut scopes: Vec<u32> = links.iter().map(|link| link.scope).collect();
+        scopes.dedup();
+        for scope in scopes {
+            shared.join_multicast_v6(&MULTICAST_GROUP_V6, scope)?;
+        }
+        own.set_multicast_hops_v6(1)?;
+        own.set_multicast_loop_v6(true)?;
+    }
+    Ok(Sockets {
+        shared: shared.into(),
+        own: own.into(),
+    })
+}
+
+/// Broadcast and multicast datagrams are only delivered to wildcard sockets.
+/// IPv6 ones are dual-stack, so IPv4 peers that know our address can still reach us.
+fn socket(domain: Domain, port: u16, shared: bool) -> io::Result<Socket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // The system may give less, which still beats its default.
+    socket.set_recv_buffer_size(RECV_BUFFER).ok();
+    if shared {
+        // Every instance on the host gets a copy of what is broadcast.
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+    }
+    let addr = match domain {
+        Domain::IPV6 => {
+            socket.set_only_v6(false)?;
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
+        }
+        _ => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)),
+    };
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Makes multicast leave through `link`. Broadcast finds its way by the address.
+pub fn select(socket: SockRef<'_>, link: &Link) -> io::Result<()> {
+    match (link.ip, link.everyone) {
+        (IpAddr::V4(ip), IpAddr::V4(group)) if group.is_multicast() => {
+            socket.set_multicast_if_v4(&ip)
+        }
+        (IpAddr::V6(_), _) => socket.set_multicast_if_v6(link.scope),
+        _ => Ok(()),
+    }
+}
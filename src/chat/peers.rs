@@ -0,0 +1,294 @@
+use super::crypto::KEY_LEN;
+use super::message::{Capabilities, DEFAULT_CHANNEL};
+use chrono::{DateTime, Local};
+use enumn::N;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Iter;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Stable identity of a chat instance, so several can share one address.
+/// Taken from its public key, so nobody can claim it with another key.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone, Default)]
+pub struct NodeId(pub u64);
+
+impl NodeId {
+    pub fn from_key(key: &[u8; KEY_LEN]) -> Self {
+        let digest = Sha256::digest(key);
+        NodeId(u64::from_be_bytes(
+            digest[..8].try_into().unwrap_or_default(),
+        ))
+    }
+
+    /// Stands in for peers from history saved before node ids, known only by address.
+    pub fn legacy(ip: IpAddr) -> Self {
+        let bytes = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        let (high, low) = bytes.split_at(8);
+        NodeId(
+            u64::from_be_bytes(high.try_into().unwrap_or_default())
+                ^ u64::from_be_bytes(low.try_into().unwrap_or_default()),
+        )
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            16 => u64::from_str_radix(s, 16)
+                .map(NodeId)
+                .map_err(|err| format!("Bad node id '{}': {}", s, err)),
+            _ => Err(format!("Bad node id '{}'", s)),
+        }
+    }
+}
+
+/// Availability announced in heartbeats.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, N, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Presence {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+impl Presence {
+    pub fn to_code(self) -> u8 {
+        self as u8
+    }
+    pub fn from_code(code: u8) -> Self {
+        Presence::n(code).unwrap_or_default()
+    }
+    pub fn icon(&self) -> &'static str {
+        match self {
+            Presence::Online => "●",
+            Presence::Away => "🌙",
+            Presence::Busy => "⛔",
+        }
+    }
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Away => write!(f, "away"),
+            Presence::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl FromStr for Presence {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(Presence::Online),
+            "away" => Ok(Presence::Away),
+            "busy" => Ok(Presence::Busy),
+            _ => Err(format!("Unknown presence '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub name: String,
+    /// Where it last sent from, and where we answer.
+    pub addr: SocketAddr,
+    pub last_seen: DateTime<Local>,
+    /// Rooms joined besides the default one.
+    pub channels: HashSet<String>,
+    pub presence: Presence,
+    /// Custom status text, may be empty.
+    pub status: String,
+    /// What it announced in `Enter`, nothing until then.
+    pub capabilities: Capabilities,
+    /// Timestamp and id of the last heartbeat or name it proved itself
+    /// with. Older ones are replays.
+    proof: (u64, u64),
+}
+
+impl Peer {
+    fn new(name: &str, addr: SocketAddr) -> Self {
+        Peer {
+            name: name.to_string(),
+            addr,
+            last_seen: Local::now(),
+            channels: HashSet::new(),
+            presence: Presence::default(),
+            status: String::new(),
+            capabilities: Capabilities::default(),
+            proof: (0, 0),
+        }
+    }
+}
+
+/// Everyone we have heard from, keyed by node.
+#[derive(Default)]
+pub struct Peers(HashMap<NodeId, Peer>);
+
+impl Peers {
+    /// Registers a peer we didn't know, returning `true` if so. Known ones
+    /// are left as they are: anyone can send their `Enter` again.
+    pub fn enter(&mut self, node: NodeId, addr: SocketAddr, name: &str) -> bool {
+        match self.0.contains_key(&node) {
+            true => false,
+            false => {
+                self.0.insert(node, Peer::new(name, addr));
+                true
+            }
+        }
+    }
+
+    /// Takes the nickname a peer sent sealed. Returns `true` if it changed.
+    pub fn rename(&mut self, node: NodeId, name: &str) -> bool {
+        match self.0.get_mut(&node) {
+            Some(peer) if !name.is_empty() && peer.name != name => {
+                peer.name = name.to_string();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds a peer we didn't know, without a nickname. Returns `true` if so.
+    pub fn seen(&mut self, node: NodeId, addr: SocketAddr) -> bool {
+        self.enter(node, addr, "")
+    }
+
+    /// Takes a heartbeat or name that the peer sealed as proof that it is
+    /// still around, at `addr`. Returns `false` for a replay of an older one,
+    /// which proves nothing, and for peers we don't know.
+    pub fn proven(&mut self, node: NodeId, addr: SocketAddr, timestamp: u64, id: u64) -> bool {
+        match self.0.get_mut(&node) {
+            Some(peer) if timestamp >= peer.proof.0 && (timestamp, id) != peer.proof => {
+                peer.proof = (timestamp, id);
+                peer.addr = addr;
+                peer.last_seen = Local::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Updates what a peer said about itself. Returns `true` if anything changed.
+    pub fn presence(&mut self, node: NodeId, presence: Presence, status: &str) -> bool {
+        match self.0.get_mut(&node) {
+            Some(peer) if peer.presence != presence || peer.status != status => {
+                peer.presence = presence;
+                peer.status = status.to_string();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_capabilities(&mut self, node: NodeId, capabilities: Capabilities) {
+        if let Some(peer) = self.0.get_mut(&node) {
+            peer.capabilities = capabilities;
+        }
+    }
+
+    pub fn supports(&self, node: &NodeId, capabilities: Capabilities) -> bool {
+        self.0
+            .get(node)
+            .is_some_and(|p| p.capabilities.contains(capabilities))
+    }
+
+    pub fn join(&mut self, node: NodeId, addr: SocketAddr, channel: &str) {
+        self.seen(node, addr);
+        if let Some(peer) = self.0.get_mut(&node) {
+            peer.channels.insert(channel.to_string());
+        }
+    }
+
+    pub fn leave(&mut self, node: &NodeId, channel: &str) {
+        if let Some(peer) = self.0.get_mut(node) {
+            peer.channels.remove(channel);
+        }
+    }
+
+    /// Everyone in `channel`.
+    pub fn in_channel<'a>(&'a self, channel: &'a str) -> impl Iterator<Item = NodeId> + 'a {
+        self.0
+            .iter()
+            .filter(move |(_, p)| channel == DEFAULT_CHANNEL || p.channels.contains(channel))
+            .map(|(node, _)| *node)
+    }
+
+    /// Forgets peers silent for longer than `timeout` and returns them.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<NodeId> {
+        let deadline =
+            Local::now() - chrono::Duration::from_std(timeout).unwrap_or(chrono::Duration::zero());
+        let gone: Vec<NodeId> = self
+            .0
+            .iter()
+            .filter(|(_, p)| p.last_seen < deadline)
+            .map(|(node, _)| *node)
+            .collect();
+        gone.iter().for_each(|node| {
+            self.0.remove(node);
+        });
+        gone
+    }
+
+    pub fn remove(&mut self, node: &NodeId) -> Option<Peer> {
+        self.0.remove(node)
+    }
+
+    /// Nickname of a peer, if it has announced one.
+    pub fn name(&self, node: &NodeId) -> Option<&str> {
+        self.0
+            .get(node)
+            .map(|p| p.name.as_str())
+            .filter(|n| !n.is_empty())
+    }
+
+    pub fn get(&self, node: &NodeId) -> Option<&Peer> {
+        self.0.get(node)
+    }
+
+    pub fn addr(&self, node: &NodeId) -> Option<SocketAddr> {
+        self.0.get(node).map(|p| p.addr)
+    }
+
+    /// A peer by nickname, address or node id.
+    pub fn find(&self, target: &str) -> Option<NodeId> {
+        self.0
+            .iter()
+            .find(|(node, p)| {
+                p.name == target
+                    || p.addr.to_string() == target
+                    || p.addr.ip().to_string() == target
+                    || node.to_string() == target
+            })
+            .map(|(node, _)| *node)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_, NodeId, Peer> {
+        self.0.iter()
+    }
+}
@@ -0,0 +1,786 @@
+use super::delivery::Delivery;
+use super::message::{Message, DEFAULT_CHANNEL};
+use super::network;
+use super::peers::NodeId;
+use super::HistoryEntry;
+use log::{info, warn};
+use rusqlite::{params, Connection, Row, ToSql, Transaction};
+use std::net::IpAddr;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Schema changes in the order they were made.
+/// `PRAGMA user_version` counts those a history went through.
+const MIGRATIONS: &[Migration] = &[messages, search_index];
+
+type Migration = fn(&Transaction, NodeId) -> rusqlite::Result<()>;
+
+/// A change to the history, returning what to show once it is done.
+type Write = Box<dyn FnOnce(&Connection) -> rusqlite::Result<&'static str> + Send>;
+
+/// Values of `messages.kind`.
+const TEXT: &str = "text";
+const OFFER: &str = "offer";
+
+/// Most search results shown at once, the newest ones.
+pub const SEARCH_LIMIT: usize = 200;
+/// Marks around matches in what `highlight()` returns.
+const MATCH_START: char = '\u{1}';
+const MATCH_END: char = '\u{2}';
+
+/// What to look for in the history. Filters left empty match everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    /// Words to find, each one also as the start of a longer word.
+    pub text: String,
+    pub sender: Option<NodeId>,
+    /// A room, leaving out direct conversations.
+    pub channel: Option<String>,
+    /// Sent at or after, in seconds since UNIX epoch.
+    pub since: Option<u64>,
+    /// Sent before.
+    pub until: Option<u64>,
+}
+
+/// A message found by a search.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub entry: HistoryEntry,
+    /// Byte ranges of the text that matched.
+    pub matches: Vec<Range<usize>>,
+}
+
+/// The history on disk.
+///
+/// Every text and file offer is one row of `messages`, keyed by sender and
+/// message id: who sent it and under which nickname, when it was sent and
+/// received, the channel or the other side of a direct conversation,
+/// whether it is a text or a file offer, and for our own messages whether
+/// they got through. The texts are indexed in `messages_fts` for `search`.
+///
+/// Reads are done right away. Writes go in order to a thread with a
+/// connection of its own, so nobody waits for the disk to sync.
+pub struct Store {
+    db: Option<Connection>,
+    path: Option<PathBuf>,
+    /// Takes the writes once the schema is up to date.
+    writer: Option<(mpsc::Sender<Write>, JoinHandle<()>)>,
+    /// We, as the sender of outgoing messages.
+    own: NodeId,
+    status: Arc<Mutex<String>>,
+}
+
+impl Store {
+    pub fn open(path: Option<PathBuf>, own: NodeId) -> Self {
+        let (db, status) = match &path {
+            Some(path) => (Connection::open(path).ok(), "DB: ready.".to_string()),
+            None => (None, "DB! offline".to_string()),
+        };
+        warn!("{}", status);
+        Store {
+            db,
+            path,
+            writer: None,
+            own,
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    pub fn status(&self) -> String {
+        self.status.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    fn set_status(&self, status: String) {
+        if let Ok(mut s) = self.status.lock() {
+            *s = status;
+        }
+    }
+
+    /// Brings the schema up to date, one migration at a time.
+    pub fn migrate(&mut self) {
+        if let Some(db) = &mut self.db {
+            let status = match migrate(db, self.own) {
+                Ok(version) if version > MIGRATIONS.len() => {
+                    format!("DB! version {} is newer than this chat.", version)
+                }
+                Ok(_) => {
+                    self.writer = self.path.clone().and_then(|path| {
+                        let (writes, queue) = mpsc::channel();
+                        let status = Arc::clone(&self.status);
+                        std::thread::Builder::new()
+                            .name("udp_chat-db".to_string())
+                            .spawn(move || write(path, queue, status))
+                            .ok()
+                            .map(|thread| (writes, thread))
+                    });
+                    "DB is ready.".to_string()
+                }
+                Err(err) => format!("DB Err: {}", err),
+            };
+            warn!("{}", status);
+            self.set_status(status);
+        }
+    }
+
+    /// Queues a write for the writer thread.
+    fn write(
+        &self,
+        write: impl FnOnce(&Connection) -> rusqlite::Result<&'static str> + Send + 'static,
+    ) {
+        if let Some((writes, _)) = &self.writer {
+            writes.send(Box::new(write)).ok();
+        }
+    }
+
+    /// Stores a text or an offer, ours or received just now.
+    pub fn save(&mut self, entry: &HistoryEntry) {
+        let outgoing = entry.node == self.own;
+        let entry = entry.clone();
+        let received = now() as i64;
+        self.write(move |db| {
+            db.execute(
+                "INSERT INTO messages
+                (id, sender, name, sent, received, channel, peer, outgoing, delivery, kind, text)
+                values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    entry.id as i64,
+                    entry.node.to_string(),
+                    entry.name,
+                    entry.timestamp as i64,
+                    received,
+                    entry.channel,
+                    entry.direct.map(|node| node.to_string()),
+                    outgoing,
+                    entry.delivery.filter(|_| outgoing).map(delivery_name),
+                    match entry.file {
+                        Some(_) => OFFER,
+                        None => TEXT,
+                    },
+                    entry.text,
+                ],
+            )
+            .map(|_| "DB: appended.")
+        });
+    }
+
+    /// Remembers how one of our messages fared.
+    pub fn set_delivery(&mut self, id: u64, delivery: Delivery) {
+        let own = self.own.to_string();
+        self.write(move |db| {
+            db.execute(
+                "UPDATE messages SET delivery = ?1 WHERE sender = ?2 AND id = ?3",
+                params![delivery_name(delivery), own, id as i64],
+            )
+            .map(|_| "DB: delivery noted.")
+        });
+    }
+
+    /// The newest `limit` messages of the whole history, in the order stored.
+    pub fn latest(&self, limit: usize) -> rusqlite::Result<Vec<HistoryEntry>> {
+        match &self.db {
+            Some(db) => {
+                let mut stmt = db.prepare(
+                    "SELECT id, sender, name, sent, text, channel, peer, outgoing, delivery, rowid
+                    FROM messages ORDER BY rowid DESC LIMIT ?1",
+                )?;
+                let mut entries = stmt
+                    .query_map([limit as i64], entry)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                entries.reverse();
+                Ok(entries)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Up to `limit` messages of a room or a direct conversation stored
+    /// before row `before`, or the newest ones without it.
+    /// Oldest first, in the order `latest` keeps.
+    pub fn older(
+        &self,
+        direct: Option<NodeId>,
+        channel: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Ok(Vec::new()),
+        };
+        let mut sql =
+            "SELECT id, sender, name, sent, text, channel, peer, outgoing, delivery, rowid
+            FROM messages WHERE "
+                .to_string();
+        let peer = direct.map(|node| node.to_string());
+        let limit = limit as i64;
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![(":limit", &limit)];
+        match &peer {
+            Some(peer) => {
+                sql.push_str("peer = :peer");
+                params.push((":peer", peer));
+            }
+            None => {
+                sql.push_str("peer IS NULL AND channel = :channel");
+                params.push((":channel", &channel));
+            }
+        }
+        if let Some(before) = &before {
+            sql.push_str(" AND rowid < :before");
+            params.push((":before", before));
+        }
+        sql.push_str(" ORDER BY rowid DESC LIMIT :limit");
+        let mut stmt = db.prepare(&sql)?;
+        let mut entries = stmt
+            .query_map(&*params, entry)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Peers we had a direct conversation with, the earliest one first.
+    pub fn conversations(&self) -> Vec<NodeId> {
+        self.db
+            .as_ref()
+            .and_then(|db| {
+                let mut stmt = db
+                    .prepare(
+                        "SELECT peer FROM messages WHERE peer IS NOT NULL
+                        GROUP BY peer ORDER BY min(sent)",
+                    )
+                    .ok()?;
+                let rows = stmt
+                    .query_map([], |row| row.get::<_, String>(0))
+                    .ok()?
+                    .filter_map(|row| row.ok()?.parse().ok())
+                    .collect();
+                Some(rows)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Whether message `id` of `sender` is in the history already.
+    pub fn contains(&self, sender: NodeId, id: u64) -> bool {
+        self.db.as_ref().is_some_and(|db| {
+            db.query_row(
+                "SELECT 1 FROM messages WHERE sender = ?1 AND id = ?2",
+                params![sender.to_string(), id as i64],
+                |_| Ok(()),
+            )
+            .is_ok()
+        })
+    }
+
+    /// One of our own texts ready to be repeated, and its direct recipient.
+    /// Offers are never rebuilt from what the history shows of them.
+    pub fn own_message(&self, id: u64) -> Option<(Message, Option<NodeId>)> {
+        self.db
+            .as_ref()?
+            .query_row(
+                "SELECT text, sent, peer, channel FROM messages
+                WHERE sender = ?1 AND id = ?2 AND kind = ?3",
+                params![self.own.to_string(), id as i64, TEXT],
+                |row| {
+                    let text: String = row.get(0)?;
+                    let timestamp = row.get::<_, i64>(1)? as u64;
+                    let direct = row
+                        .get::<_, Option<String>>(2)?
+                        .and_then(|peer| peer.parse::<NodeId>().ok());
+                    let channel: String = row.get(3)?;
+                    let mut message =
+                        Message::retry_text(id, timestamp, &text).in_channel(&channel);
+                    message.private = direct.is_some();
+                    Ok((message, direct))
+                },
+            )
+            .ok()
+    }
+
+    /// Newest messages that match, up to `SEARCH_LIMIT`.
+    pub fn search(&self, query: &Query) -> rusqlite::Result<Vec<Hit>> {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return Ok(Vec::new()),
+        };
+        let words = fts_query(&query.text);
+        let mut sql = match words.is_empty() {
+            true => "SELECT m.id, m.sender, m.name, m.sent, m.text, m.channel, m.peer,
+                m.outgoing, m.delivery, m.rowid, m.text
+                FROM messages m WHERE 1"
+                .to_string(),
+            false => "SELECT m.id, m.sender, m.name, m.sent, m.text, m.channel, m.peer,
+                m.outgoing, m.delivery, m.rowid, highlight(messages_fts, 0, char(1), char(2))
+                FROM messages_fts JOIN messages m ON m.rowid = messages_fts.rowid
+                WHERE messages_fts MATCH :words"
+                .to_string(),
+        };
+        let sender = query.sender.map(|node| node.to_string());
+        let since = query.since.map(|time| time as i64);
+        let until = query.until.map(|time| time as i64);
+        let mut params: Vec<(&str, &dyn ToSql)> = Vec::new();
+        if !words.is_empty() {
+            params.push((":words", &words));
+        }
+        if let Some(sender) = &sender {
+            sql.push_str(" AND m.sender = :sender");
+            params.push((":sender", sender));
+        }
+        if let Some(channel) = &query.channel {
+            sql.push_str(" AND m.peer IS NULL AND m.channel = :channel");
+            params.push((":channel", channel));
+        }
+        if let Some(since) = &since {
+            sql.push_str(" AND m.sent >= :since");
+            params.push((":since", since));
+        }
+        if let Some(until) = &until {
+            sql.push_str(" AND m.sent < :until");
+            params.push((":until", until));
+        }
+        sql.push_str(&format!(
+            " ORDER BY m.sent DESC, m.received DESC LIMIT {}",
+            SEARCH_LIMIT
+        ));
+        let mut stmt = db.prepare(&sql)?;
+        let hits = stmt
+            .query_map(&*params, |row| {
+                Ok(Hit {
+                    entry: entry(row)?,
+                    matches: matches(&row.get::<_, String>(10)?),
+                })
+            })?
+            .collect();
+        hits
+    }
+
+    /// Everyone in the history under the nickname they last used.
+    pub fn senders(&self) -> Vec<(NodeId, String)> {
+        let mut senders = self
+            .db
+            .as_ref()
+            .and_then(|db| {
+                let mut stmt = db
+                    .prepare("SELECT sender, name, max(sent) FROM messages GROUP BY sender")
+                    .ok()?;
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))
+                    .ok()?
+                    .filter_map(|row| row.ok())
+                    .filter_map(|(sender, name)| Some((sender.parse().ok()?, name)))
+                    .collect::<Vec<(NodeId, String)>>();
+                Some(rows)
+            })
+            .unwrap_or_default();
+        senders.sort_by(|a, b| a.1.cmp(&b.1));
+        senders
+    }
+
+    /// Rooms anything was said in.
+    pub fn channels(&self) -> Vec<String> {
+        self.db
+            .as_ref()
+            .and_then(|db| {
+                let mut stmt = db
+                    .prepare(
+                        "SELECT DISTINCT channel FROM messages WHERE peer IS NULL ORDER BY channel",
+                    )
+                    .ok()?;
+                let rows = stmt
+                    .query_map([], |row| row.get(0))
+                    .ok()?
+                    .filter_map(|row| row.ok())
+                    .collect();
+                Some(rows)
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&mut self) {
+        self.write(|db| {
+            db.execute("DELETE FROM messages", [])
+                .map(|_| "DB: Cleared")
+        });
+    }
+}
+
+impl Drop for Store {
+    /// Waits for the queued writes.
+    fn drop(&mut self) {
+        if let Some((writes, thread)) = self.writer.take() {
+            drop(writes);
+            thread.join().ok();
+        }
+    }
+}
+
+/// Runs the writes in the order they came until the store is dropped.
+fn write(path: PathBuf, queue: mpsc::Receiver<Write>, status: Arc<Mutex<String>>) {
+    let db = match Connection::open(path) {
+        Ok(db) => db,
+        Err(err) => {
+            warn!("DB! {}", err);
+            return;
+        }
+    };
+    // Readers go on while the writer syncs.
+    if let Err(err) = db.pragma_update(None, "journal_mode", "WAL") {
+        warn!("DB! {}", err);
+    }
+    for write in queue {
+        let done = match write(&db) {
+            Ok(done) => done.to_string(),
+            Err(err) => {
+                let err = format!("DB! {}", err);
+                warn!("{}", err);
+                err
+            }
+        };
+        info!("{}", done);
+        if let Ok(mut status) = status.lock() {
+            *status = done;
+        }
+    }
+}
+
+fn migrate(db: &mut Connection, own: NodeId) -> rusqlite::Result<usize> {
+    let version = db.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    for (done, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = db.transaction()?;
+        migration(&tx, own)?;
+        tx.pragma_update(None, "user_version", done as i64 + 1)?;
+        tx.commit()?;
+        info!("DB: migrated to version {}.", done + 1);
+    }
+    Ok(version.max(MIGRATIONS.len()))
+}
+
+fn entry(row: &Row) -> rusqlite::Result<HistoryEntry> {
+    let node = row
+        .get::<_, String>(1)?
+        .parse::<NodeId>()
+        .unwrap_or_default();
+    let outgoing: bool = row.get(7)?;
+    Ok(HistoryEntry {
+        id: row.get::<_, i64>(0)? as u64,
+        node,
+        name: row.get(2)?,
+        timestamp: row.get::<_, i64>(3)? as u64,
+        text: row.get(4)?,
+        channel: row.get(5)?,
+        direct: row
+            .get::<_, Option<String>>(6)?
+            .and_then(|peer| peer.parse().ok()),
+        // Whatever was still on its way got lost with the last session.
+        delivery: match row.get::<_, Option<String>>(8)?.as_deref() {
+            _ if !outgoing => None,
+            Some("delivered") => Some(Delivery::Delivered),
+            Some(_) => Some(Delivery::Failed),
+            None => None,
+        },
+        file: None,
+        row: row.get(9)?,
+    })
+}
+
+/// Words for FTS5, quoted so none of them is taken for its syntax.
+fn fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Where the marks of `highlight()` were, in the text without them.
+fn matches(highlighted: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut len = 0;
+    let mut start = None;
+    for c in highlighted.chars() {
+        match c {
+            MATCH_START => start = Some(len),
+            MATCH_END => ranges.extend(start.take().map(|start| start..len)),
+            c => len += c.len_utf8(),
+        }
+    }
+    ranges
+}
+
+fn delivery_name(delivery: Delivery) -> &'static str {
+    match delivery {
+        Delivery::Sending => "sending",
+        Delivery::Delivered => "delivered",
+        Delivery::Failed => "failed",
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn has_column(tx: &Transaction, table: &str, column: &str) -> bool {
+    tx.prepare(&format!("SELECT {} FROM {} LIMIT 0", column, table))
+        .is_ok()
+}
+
+/// 1: one table for the room and direct histories, with senders by node id
+/// and whether a row is a text or a file offer.
+fn messages(tx: &Transaction, own: NodeId) -> rusqlite::Result<()> {
+    tx.execute_batch(&format!(
+        "CREATE TABLE messages (
+            id integer not null,
+            sender text not null,
+            name text not null default '',
+            sent integer not null default 0,
+            received integer not null default 0,
+            channel text not null default 'general',
+            peer text,
+            outgoing integer not null default 0,
+            delivery text,
+            kind text not null default '{}',
+            text text not null,
+            primary key (sender, id)
+        );
+        CREATE INDEX messages_by_time ON messages (sent);",
+        TEXT
+    ))?;
+    // Older rows name senders by address, ours by any of our addresses.
+    let own_ips = network::own_ips();
+    let node_of = |text: &str| match text.parse::<NodeId>() {
+        Ok(node) => Some(node),
+        Err(_) => match text.parse::<IpAddr>().ok()? {
+            ip if own_ips.contains(&ip) => Some(own),
+            ip => Some(NodeId::legacy(ip)),
+        },
+    };
+    for (table, direct) in [("chat_history", false), ("direct_history", true)] {
+        if !has_column(tx, table, "ip") {
+            continue;
+        }
+        // Each of these came with a later version of the table.
+        let name = match has_column(tx, table, "name") {
+            true => "name",
+            false => "''",
+        };
+        // Before that, the id was the time in seconds.
+        let timestamp = match has_column(tx, table, "timestamp") {
+            true => "timestamp",
+            false => "id",
+        };
+        let channel = match has_column(tx, table, "channel") {
+            true => "channel".to_string(),
+            false => format!("'{}'", DEFAULT_CHANNEL),
+        };
+        let peer = match direct {
+            true => "peer",
+            false => "NULL",
+        };
+        let rows = {
+            let mut stmt = tx.prepare(&format!(
+                "SELECT id, ip, {}, {}, {}, {}, message_text FROM {}",
+                name, timestamp, channel, peer, table
+            ))?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?,
+                        row.get::<_, Option<String>>(5)?,
+                        row.get::<_, String>(6)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows
+        };
+        for (id, ip, name, timestamp, channel, peer, text) in rows {
+            let sender = match node_of(&ip) {
+                Some(node) => node,
+                None => continue,
+            };
+            let name = match name.is_empty() {
+                true => ip,
+                false => name,
+            };
+            tx.execute(
+                "INSERT OR IGNORE INTO messages
+                (id, sender, name, sent, received, channel, peer, outgoing, text)
+                values (?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    id,
+                    sender.to_string(),
+                    name,
+                    timestamp,
+                    channel,
+                    peer.as_deref()
+                        .and_then(node_of)
+                        .map(|node| node.to_string()),
+                    sender == own,
+                    text,
+                ],
+            )?;
+        }
+        tx.execute_batch(&format!("DROP TABLE {}", table))?;
+    }
+    Ok(())
+}
+
+/// 2: full-text index of the texts, kept up to date by triggers.
+fn search_index(tx: &Transaction, _: NodeId) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE messages_fts USING fts5(
+            text,
+            content = 'messages',
+            content_rowid = 'rowid',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, text) VALUES (new.rowid, new.text);
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, text)
+            VALUES ('delete', old.rowid, old.text);
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF text ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, text)
+            VALUES ('delete', old.rowid, old.text);
+            INSERT INTO messages_fts (rowid, text) VALUES (new.rowid, new.text);
+        END;
+        INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN: NodeId = NodeId(7);
+
+    fn store(db: Connection) -> Store {
+        Store {
+            db: Some(db),
+            path: None,
+            writer: None,
+            own: OWN,
+            status: Arc::default(),
+        }
+    }
+
+    fn version(store: &Store) -> i64 {
+        let db = store.db.as_ref().unwrap();
+        db.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_the_first_schema() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "create table if not exists chat_history (
+                id integer primary key,
+                ip text not null,
+                message_text text not null
+            );
+            INSERT INTO chat_history VALUES (1600000000, '192.0.2.7', 'hello there');
+            INSERT INTO chat_history VALUES (1600000060, '192.0.2.8', '📎 cat.png (3 KB)');",
+        )
+        .unwrap();
+        let mut store = store(db);
+        store.migrate();
+        assert_eq!(store.status(), "DB is ready.");
+        assert_eq!(version(&store), MIGRATIONS.len() as i64);
+        let history = store.latest(10).unwrap();
+        assert_eq!(history.len(), 2);
+        let first = &history[0];
+        assert_eq!(first.id, 1600000000);
+        assert_eq!(first.node, NodeId::legacy("192.0.2.7".parse().unwrap()));
+        assert_eq!(first.name, "192.0.2.7");
+        assert_eq!(first.timestamp, 1600000000);
+        assert_eq!(first.channel, DEFAULT_CHANNEL);
+        assert_eq!(first.direct, None);
+        assert_eq!(first.text, "hello there");
+        assert!(store.contains(first.node, first.id));
+        assert!(!store.contains(OWN, first.id));
+        let kinds: Vec<String> = {
+            let db = store.db.as_ref().unwrap();
+            let mut stmt = db
+                .prepare("SELECT kind FROM messages ORDER BY rowid")
+                .unwrap();
+            let kinds = stmt.query_map([], |row| row.get(0)).unwrap();
+            kinds.collect::<rusqlite::Result<_>>().unwrap()
+        };
+        // Offers were never stored before, whatever a text looks like.
+        assert_eq!(kinds, [TEXT, TEXT]);
+        let query = Query {
+            text: "hel".to_string(),
+            ..Query::default()
+        };
+        let hits = store.search(&query).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].entry.text, "hello there");
+        assert_eq!(hits[0].matches, vec![Range { start: 0, end: 5 }]);
+    }
+
+    #[test]
+    fn pages_back_through_a_room() {
+        let mut store = store(Connection::open_in_memory().unwrap());
+        store.migrate();
+        let db = store.db.as_ref().unwrap();
+        // All sent within the same second, in the room and around it.
+        for (id, channel, peer) in [
+            (0, DEFAULT_CHANNEL, None),
+            (1, DEFAULT_CHANNEL, None),
+            (2, "lunch", None),
+            (3, DEFAULT_CHANNEL, Some(OWN.to_string())),
+            (4, DEFAULT_CHANNEL, None),
+            (5, DEFAULT_CHANNEL, None),
+            (6, DEFAULT_CHANNEL, None),
+        ] {
+            db.execute(
+                "INSERT INTO messages (id, sender, sent, channel, peer, text)
+                values (?1, ?2, 1600000000, ?3, ?4, 'hi')",
+                params![id, NodeId(1).to_string(), channel, peer],
+            )
+            .unwrap();
+        }
+        let page = |before: Option<i64>| {
+            let entries = store.older(None, DEFAULT_CHANNEL, before, 2).unwrap();
+            let ids: Vec<u64> = entries.iter().map(|e| e.id).collect();
+            (ids, entries.first().and_then(|e| e.row))
+        };
+        let (ids, first) = page(None);
+        assert_eq!(ids, [5, 6]);
+        let (ids, first) = page(first);
+        assert_eq!(ids, [1, 4]);
+        let (ids, first) = page(first);
+        assert_eq!(ids, [0]);
+        let (ids, _) = page(first);
+        assert!(ids.is_empty());
+        let direct = store.older(Some(OWN), DEFAULT_CHANNEL, None, 10).unwrap();
+        assert_eq!(direct.iter().map(|e| e.id).collect::<Vec<_>>(), [3]);
+    }
+
+    #[test]
+    fn migrates_once() {
+        let mut store = store(Connection::open_in_memory().unwrap());
+        store.migrate();
+        store.migrate();
+        assert_eq!(store.status(), "DB is ready.");
+        assert_eq!(version(&store), MIGRATIONS.len() as i64);
+        let newer = MIGRATIONS.len() as i64 + 1;
+        store
+            .db
+            .as_ref()
+            .unwrap()
+            .pragma_update(None, "user_version", newer)
+            .unwrap();
+        store.migrate();
+        assert!(store.status().starts_with("DB! version"));
+    }
+}
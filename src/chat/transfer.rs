@@ -0,0 +1,782 @@
+use super::message::Message;
+use super::peers::NodeId;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const HASH_LEN: usize = 32;
+/// Bytes of a file in one `Chunk`, leaves room for the transfer id,
+/// the index and the seal within a single datagram.
+pub const CHUNK_LEN: usize = 960;
+/// Chunks asked for at once.
+pub const WINDOW: usize = 32;
+/// How long to wait for requested chunks before asking again.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Requests in a row without a single new chunk before a download stalls.
+pub const MAX_STALLS: u8 = 5;
+/// Largest file we offer or accept.
+pub const MAX_FILE: u64 = 16 << 30;
+/// Suffix of downloads that are not complete yet.
+const PARTIAL: &str = "part";
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TransferState {
+    /// Waiting for us to accept it.
+    Offered,
+    Running,
+    Paused,
+    Done,
+    /// Stalled or corrupted, can be resumed.
+    Failed,
+    Cancelled,
+}
+
+/// A file we send or receive, split into chunks.
+///
+/// Downloads are driven by the receiver: it asks for a window of missing
+/// chunks, asks again for the ones that didn't come, and can stop and
+/// resume at any moment. The finished file is checked against the SHA-256
+/// from the offer.
+pub struct Transfer {
+    /// Id of the `Offer`.
+    pub id: u64,
+    /// Sender of a download, or ourselves for an upload.
+    pub node: NodeId,
+    pub name: String,
+    pub size: u64,
+    hash: [u8; HASH_LEN],
+    /// Source of an upload, destination of a download.
+    pub path: PathBuf,
+    pub incoming: bool,
+    pub state: TransferState,
+    /// Chunks received, or sent at least once. Left empty for downloads
+    /// until they are accepted.
+    done: Bitset,
+    /// Peers that may ask for chunks and haven't finished yet.
+    recipients: HashSet<NodeId>,
+    requested: Vec<u32>,
+    asked: Instant,
+    stalls: u8,
+    offer: Option<Message>,
+    /// The complete download is being checked.
+    checking: bool,
+}
+
+impl Transfer {
+    pub fn progress(&self) -> f32 {
+        match self.done.len() {
+            0 if self.size > 0 => 0.0,
+            0 => 1.0,
+            total => self.done.count() as f32 / total as f32,
+        }
+    }
+
+    fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        self.done.missing().map(|i| i as u32)
+    }
+
+    fn partial_path(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".");
+        name.push(PARTIAL);
+        PathBuf::from(name)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done.len() == chunks(self.size) && self.done.count() == self.done.len()
+    }
+}
+
+/// One bit per chunk, so even the largest file takes little memory.
+#[derive(Default)]
+struct Bitset {
+    words: Vec<u64>,
+    len: usize,
+    ones: usize,
+}
+
+impl Bitset {
+    fn new(len: usize) -> Self {
+        Bitset {
+            words: vec![0; len.div_ceil(64)],
+            len,
+            ones: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Bits that are set.
+    fn count(&self) -> usize {
+        self.ones
+    }
+
+    fn get(&self, i: usize) -> Option<bool> {
+        (i < self.len).then(|| self.words[i / 64] >> (i % 64) & 1 == 1)
+    }
+
+    fn set(&mut self, i: usize) {
+        if self.get(i) == Some(false) {
+            self.words[i / 64] |= 1 << (i % 64);
+            self.ones += 1;
+        }
+    }
+
+    fn fill(&mut self, value: bool) {
+        let word = if value { u64::MAX } else { 0 };
+        self.words.iter_mut().for_each(|w| *w = word);
+        let tail = self.len % 64;
+        if value && tail != 0 {
+            if let Some(last) = self.words.last_mut() {
+                *last = (1 << tail) - 1;
+            }
+        }
+        self.ones = if value { self.len } else { 0 };
+    }
+
+    /// Bits that are not set, in order.
+    fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| **word != u64::MAX)
+            .flat_map(|(w, word)| {
+                (0..64)
+                    .filter(move |bit| word >> bit & 1 == 0)
+                    .map(move |bit| w * 64 + bit)
+            })
+            .take_while(move |i| *i < self.len)
+    }
+}
+
+/// Disk work of a transfer, done by `run` away from the front-end.
+pub enum Job {
+    /// Sizes and hashes a file to be offered as `id`.
+    Hash { id: u64, path: PathBuf },
+    /// Reads chunks a recipient asked for.
+    Read {
+        node: NodeId,
+        id: u64,
+        path: PathBuf,
+        chunks: Vec<u32>,
+    },
+    Write {
+        id: u64,
+        path: PathBuf,
+        index: u32,
+        bytes: Vec<u8>,
+    },
+    /// Checks a complete download and moves it in place.
+    Finish {
+        id: u64,
+        partial: PathBuf,
+        path: PathBuf,
+        size: u64,
+        hash: [u8; HASH_LEN],
+    },
+}
+
+/// What came of a `Job`.
+pub enum Done {
+    Hashed {
+        id: u64,
+        path: PathBuf,
+        result: io::Result<(u64, [u8; HASH_LEN])>,
+    },
+    Read {
+        node: NodeId,
+        id: u64,
+        chunks: Vec<(u32, Vec<u8>)>,
+    },
+    Written {
+        id: u64,
+        index: u32,
+        result: io::Result<()>,
+    },
+    Finished {
+        id: u64,
+        result: io::Result<()>,
+    },
+}
+
+impl Job {
+    pub fn run(self) -> Done {
+        match self {
+            Job::Hash { id, path } => Done::Hashed {
+                id,
+                // It may have grown since `prepare`.
+                result: hash_file(&path).and_then(|(size, hash)| match size > MAX_FILE {
+                    true => Err(too_large()),
+                    false => Ok((size, hash)),
+                }),
+                path,
+            },
+            Job::Read {
+                node,
+                id,
+                path,
+                chunks,
+            } => Done::Read {
+                node,
+                id,
+                chunks: read_chunks(id, &path, &chunks),
+            },
+            Job::Write {
+                id,
+                path,
+                index,
+                bytes,
+            } => Done::Written {
+                id,
+                index,
+                result: write_chunk(&path, index, &bytes),
+            },
+            Job::Finish {
+                id,
+                partial,
+                path,
+                size,
+                hash,
+            } => Done::Finished {
+                id,
+                result: finish(&partial, &path, size, hash),
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Transfers {
+    transfers: HashMap<u64, Transfer>,
+    /// Transfers that ended since the last call to `changes`.
+    changes: Vec<(u64, TransferState)>,
+    /// Disk work to be done, taken by `jobs`.
+    jobs: Vec<Job>,
+}
+
+impl Transfers {
+    /// Has a file hashed, to be offered as the returned id once it is `Done::Hashed`.
+    pub fn prepare(&mut self, path: &Path) -> io::Result<u64> {
+        if path.file_name().is_none() || !path.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a file"));
+        }
+        if path.metadata()?.len() > MAX_FILE {
+            return Err(too_large());
+        }
+        let id = rand::random();
+        self.jobs.push(Job::Hash {
+            id,
+            path: path.to_owned(),
+        });
+        Ok(id)
+    }
+
+    /// Registers our hashed file and returns the `Offer` for it.
+    pub fn offer(
+        &mut self,
+        id: u64,
+        node: NodeId,
+        path: &Path,
+        (size, hash): (u64, [u8; HASH_LEN]),
+        recipients: HashSet<NodeId>,
+    ) -> Message {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut offer = Message::offer(size, &hash, &name);
+        offer.id = id;
+        info!(
+            "Offering '{}' ({}) as #{:016x}",
+            name,
+            size_label(size),
+            offer.id
+        );
+        self.transfers.insert(
+            offer.id,
+            Transfer {
+                id: offer.id,
+                node,
+                name,
+                size,
+                hash,
+                path: path.to_owned(),
+                incoming: false,
+                state: TransferState::Running,
+                done: Bitset::new(chunks(size)),
+                recipients,
+                requested: Vec::new(),
+                asked: Instant::now(),
+                stalls: 0,
+                offer: Some(offer.clone()),
+                checking: false,
+            },
+        );
+        offer
+    }
+
+    /// Registers a file offered by a peer. Nothing is written, nor kept
+    /// per chunk, until it is accepted.
+    pub fn offered(&mut self, node: NodeId, offer: &Message, dir: &Path) -> bool {
+        let (size, hash, name) = match offer.read_offer() {
+            Some(offer) => offer,
+            None => return false,
+        };
+        if self.transfers.contains_key(&offer.id) {
+            return false;
+        }
+        let name = safe_name(&name);
+        self.transfers.insert(
+            offer.id,
+            Transfer {
+                id: offer.id,
+                node,
+                path: dir.join(&name),
+                name,
+                size,
+                hash,
+                incoming: true,
+                state: TransferState::Offered,
+                done: Bitset::default(),
+                recipients: HashSet::from([node]),
+                requested: Vec::new(),
+                asked: Instant::now(),
+                stalls: 0,
+                offer: None,
+                checking: false,
+            },
+        );
+        true
+    }
+
+    pub fn changes(&mut self) -> Vec<(u64, TransferState)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub fn jobs(&mut self) -> Vec<Job> {
+        std::mem::take(&mut self.jobs)
+    }
+
+    pub fn get(&self, id: &u64) -> Option<&Transfer> {
+        self.transfers.get(id)
+    }
+
+    /// Our own offer, to repeat it to a recipient who missed it.
+    pub fn offer_for(&self, node: &NodeId, id: &u64) -> Option<&Message> {
+        let t = self.transfers.get(id)?;
+        t.recipients.contains(node).then_some(t.offer.as_ref()?)
+    }
+
+    /// Starts or continues a download. Chunks already on disk are kept.
+    pub fn resume(&mut self, id: u64) {
+        if let Some(t) = self.transfers.get_mut(&id) {
+            if t.incoming && t.state != TransferState::Done && t.state != TransferState::Cancelled {
+                if let Some(dir) = t.path.parent() {
+                    std::fs::create_dir_all(dir).ok();
+                    if t.state == TransferState::Offered {
+                        t.path = destination(dir, &t.name);
+                    }
+                }
+                if t.done.len() != chunks(t.size) {
+                    t.done = Bitset::new(chunks(t.size));
+                }
+                t.state = TransferState::Running;
+                t.requested.clear();
+                t.stalls = 0;
+            }
+        }
+    }
+
+    pub fn pause(&mut self, id: u64) {
+        if let Some(t) = self.transfers.get_mut(&id) {
+            if t.incoming && t.state == TransferState::Running {
+                t.state = TransferState::Paused;
+            }
+        }
+    }
+
+    /// Stops a transfer and returns whom to tell about it.
+    pub fn cancel(&mut self, id: u64) -> Vec<NodeId> {
+        match self.transfers.get_mut(&id) {
+            Some(t) if t.state != TransferState::Done && t.state != TransferState::Cancelled => {
+                t.state = TransferState::Cancelled;
+                self.changes.push((id, t.state));
+                if t.incoming {
+                    std::fs::remove_file(t.partial_path()).ok();
+                }
+                t.recipients.drain().collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The other side gave up on a transfer.
+    pub fn cancelled(&mut self, node: NodeId, id: u64) {
+        if let Some(t) = self.transfers.get_mut(&id) {
+            if t.incoming && t.node == node {
+                self.cancel(id);
+            } else {
+                t.recipients.remove(&node);
+            }
+        }
+    }
+
+    /// Next chunks to ask for, by sender and transfer. Finished downloads ask for nothing.
+    pub fn due(&mut self) -> Vec<(NodeId, u64, Vec<u32>)> {
+        let now = Instant::now();
+        let mut requests = Vec::new();
+        for t in self
+            .transfers
+            .values_mut()
+            .filter(|t| t.incoming && t.state == TransferState::Running && !t.checking)
+        {
+            if t.is_complete() {
+                t.checking = true;
+                self.jobs.push(Job::Finish {
+                    id: t.id,
+                    partial: t.partial_path(),
+                    path: t.path.to_owned(),
+                    size: t.size,
+                    hash: t.hash,
+                });
+                continue;
+            }
+            let waiting = t
+                .requested
+                .iter()
+                .any(|i| t.done.get(*i as usize) == Some(false));
+            if waiting && now - t.asked < CHUNK_TIMEOUT {
+                continue;
+            }
+            if waiting {
+                t.stalls += 1;
+                if t.stalls > MAX_STALLS {
+                    warn!("Transfer #{:016x} stalled.", t.id);
+                    t.state = TransferState::Failed;
+                    self.changes.push((t.id, t.state));
+                    continue;
+                }
+            }
+            t.requested = t.missing().take(WINDOW).collect();
+            t.asked = now;
+            requests.push((t.node, t.id, t.requested.clone()));
+        }
+        requests
+    }
+
+    /// Has a received chunk written.
+    pub fn chunk(&mut self, node: NodeId, id: u64, index: u32, bytes: &[u8]) {
+        if let Some(t) = self.transfers.get_mut(&id) {
+            let expected = t.size.saturating_sub(index as u64 * CHUNK_LEN as u64);
+            if !t.incoming
+                || t.node != node
+                || t.state != TransferState::Running
+                || t.done.get(index as usize) != Some(false)
+                || bytes.len() as u64 != expected.min(CHUNK_LEN as u64)
+            {
+                return;
+            }
+            self.jobs.push(Job::Write {
+                id,
+                path: t.partial_path(),
+                index,
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+
+    /// Has the chunks a recipient asked for read. An empty request means it got the whole file.
+    pub fn serve(&mut self, node: NodeId, id: u64, wanted: &[u32]) {
+        let t = match self.transfers.get_mut(&id) {
+            Some(t) if !t.incoming && t.recipients.contains(&node) => t,
+            _ => return,
+        };
+        if wanted.is_empty() {
+            t.recipients.remove(&node);
+            if t.recipients.is_empty() {
+                t.done.fill(true);
+                t.state = TransferState::Done;
+                self.changes.push((id, t.state));
+            }
+            return;
+        }
+        let total = t.done.len();
+        self.jobs.push(Job::Read {
+            node,
+            id,
+            path: t.path.to_owned(),
+            chunks: wanted
+                .iter()
+                .copied()
+                .filter(|i| (*i as usize) < total)
+                .collect(),
+        });
+    }
+
+    /// Takes in finished disk work and returns what to send to whom.
+    /// Hashed files are offered by the caller, who knows to whom.
+    pub fn done(&mut self, done: Done) -> Vec<(NodeId, Message)> {
+        match done {
+            Done::Hashed { .. } => vec![],
+            Done::Read { node, id, chunks } => match self.transfers.get_mut(&id) {
+                Some(t) if t.state != TransferState::Cancelled => chunks
+                    .into_iter()
+                    .map(|(index, bytes)| {
+                        t.done.set(index as usize);
+                        (node, Message::chunk(id, index, &bytes))
+                    })
+                    .collect(),
+                _ => vec![],
+            },
+            Done::Written { id, index, result } => {
+                if let Some(t) = self.transfers.get_mut(&id) {
+                    match (result, t.state) {
+                        // It was cancelled while we wrote.
+                        (_, TransferState::Cancelled) => {
+                            std::fs::remove_file(t.partial_path()).ok();
+                        }
+                        (Ok(_), _) => {
+                            t.done.set(index as usize);
+                            t.stalls = 0;
+                        }
+                        (Err(err), TransferState::Running) => {
+                            warn!("Transfer #{:016x}! {}", id, err);
+                            t.state = TransferState::Failed;
+                            self.changes.push((id, t.state));
+                        }
+                        (Err(_), _) => (),
+                    }
+                }
+                vec![]
+            }
+            Done::Finished { id, result } => {
+                let t = match self.transfers.get_mut(&id) {
+                    Some(t) if t.state != TransferState::Cancelled => t,
+                    _ => return vec![],
+                };
+                t.checking = false;
+                t.state = match result {
+                    Ok(_) => TransferState::Done,
+                    Err(err) => {
+                        warn!("Transfer #{:016x}! {}", id, err);
+                        t.done.fill(false);
+                        TransferState::Failed
+                    }
+                };
+                self.changes.push((id, t.state));
+                match t.state {
+                    TransferState::Done => {
+                        info!("Received '{}'", t.path.display());
+                        vec![(t.node, Message::request(id, &[]))]
+                    }
+                    _ => vec![],
+                }
+            }
+        }
+    }
+}
+
+/// Number of chunks a file of `size` bytes is split into.
+fn chunks(size: u64) -> usize {
+    size.div_ceil(CHUNK_LEN as u64) as usize
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Larger than {}", size_label(MAX_FILE)),
+    )
+}
+
+/// Size in a human friendly unit.
+pub fn size_label(size: u64) -> String {
+    match size {
+        s if s < 1 << 10 => format!("{} B", s),
+        s if s < 1 << 20 => format!("{:.1} KB", s as f64 / (1 << 10) as f64),
+        s if s < 1 << 30 => format!("{:.1} MB", s as f64 / (1 << 20) as f64),
+        s => format!("{:.1} GB", s as f64 / (1 << 30) as f64),
+    }
+}
+
+/// Whether the file looks like something we can preview.
+pub fn is_image(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .as_deref(),
+        Some("png" | "jpg" | "jpeg" | "gif" | "bmp")
+    )
+}
+
+fn hash_file(path: &Path) -> io::Result<(u64, [u8; HASH_LEN])> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, hasher.finalize().into()))
+}
+
+fn write_chunk(path: &Path, index: u32, bytes: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    file.seek(SeekFrom::Start(index as u64 * CHUNK_LEN as u64))?;
+    file.write_all(bytes)
+}
+
+/// Reads the `wanted` chunks of a file, skipping those that fail.
+fn read_chunks(id: u64, path: &Path, wanted: &[u32]) -> Vec<(u32, Vec<u8>)> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Transfer #{:016x}! {}", id, err);
+            return vec![];
+        }
+    };
+    let mut chunks = Vec::new();
+    for &index in wanted {
+        let mut buf = vec![0; CHUNK_LEN];
+        let read = file
+            .seek(SeekFrom::Start(index as u64 * CHUNK_LEN as u64))
+            .and_then(|_| read_full(&mut file, &mut buf));
+        match read {
+            Ok(len) => {
+                buf.truncate(len);
+                chunks.push((index, buf));
+            }
+            Err(err) => warn!("Transfer #{:016x}! {}", id, err),
+        }
+    }
+    chunks
+}
+
+/// Checks the finished download and moves it in place.
+fn finish(partial: &Path, path: &Path, size: u64, hash: [u8; HASH_LEN]) -> io::Result<()> {
+    if size == 0 {
+        File::create(partial)?;
+    }
+    if hash_file(partial)? != (size, hash) {
+        std::fs::remove_file(partial).ok();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Checksum mismatch",
+        ));
+    }
+    std::fs::rename(partial, path)
+}
+
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// File name without any path a peer might have sneaked in.
+fn safe_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '/' | '\\' | ':'))
+        .collect();
+    match name.trim_start_matches('.').trim() {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Free path in `dir`, numbering the name if it is taken.
+fn destination(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    (1..)
+        .map(|i| dir.join(format!("{} {}", i, name)))
+        .find(|p| !p.exists())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_chunks() {
+        assert_eq!(chunks(0), 0);
+        assert_eq!(chunks(CHUNK_LEN as u64), 1);
+        assert_eq!(chunks(CHUNK_LEN as u64 + 1), 2);
+        let mut done = Bitset::new(130);
+        done.set(0);
+        done.set(0);
+        done.set(64);
+        done.set(129);
+        done.set(130);
+        assert_eq!((done.len(), done.count()), (130, 3));
+        assert_eq!(done.get(130), None);
+        assert_eq!(done.missing().count(), 127);
+        assert_eq!(done.missing().next(), Some(1));
+        assert!(!done.missing().any(|i| i == 64 || i >= 129));
+        done.fill(true);
+        assert_eq!(done.count(), 130);
+        assert_eq!(done.missing().next(), None);
+        done.fill(false);
+        assert_eq!(done.count(), 0);
+        assert_eq!(done.missing().last(), Some(129));
+    }
+
+    #[test]
+    fn keeps_names_inside_downloads() {
+        let dir = Path::new("downloads");
+        for (name, safe) in [
+            ("cat.png", "cat.png"),
+            ("../../.bashrc", "bashrc"),
+            ("/etc/passwd", "etcpasswd"),
+            ("C:\\Windows\\win.ini", "CWindowswin.ini"),
+            ("..", "file"),
+            ("", "file"),
+            (" \n", "file"),
+        ] {
+            assert_eq!(safe_name(name), safe);
+            assert_eq!(dir.join(safe_name(name)).parent(), Some(dir));
+        }
+    }
+
+    #[test]
+    fn takes_only_expected_chunks() {
+        let dir =
+            std::env::temp_dir().join(format!("udp_chat-test-{:016x}", rand::random::<u64>()));
+        let mut transfers = Transfers::default();
+        let node = NodeId(1);
+        let size = CHUNK_LEN as u64 * 2 + 10;
+        let offer = Message::offer(size, &[0; HASH_LEN], "cat.png");
+        assert!(transfers.offered(node, &offer, &dir));
+        // Nothing is taken before the download is accepted.
+        transfers.chunk(node, offer.id, 0, &[0; CHUNK_LEN]);
+        assert!(transfers.jobs().is_empty());
+        transfers.resume(offer.id);
+        for (from, index, len) in [
+            (node, 3, 10),
+            (node, u32::MAX, 10),
+            (node, 2, CHUNK_LEN),
+            (node, 1, 10),
+            (NodeId(2), 0, CHUNK_LEN),
+        ] {
+            transfers.chunk(from, offer.id, index, &vec![0; len]);
+            assert!(transfers.jobs().is_empty(), "chunk {} taken", index);
+        }
+        transfers.chunk(node, offer.id, 2, &[0; 10]);
+        assert_eq!(transfers.jobs().len(), 1);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,274 @@
+use super::inbox::Postman;
+use super::message::{self, Message, Rejected};
+use super::network::{self, Link, Sockets};
+use super::{Wake, HEARTBEAT, TICK};
+use log::{info, warn};
+use socket2::SockRef;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+/// Threads for the network tasks. Disk work gets threads of its own.
+const WORKERS: usize = 2;
+/// How long stopping waits for queued datagrams to leave.
+const FLUSH: Duration = Duration::from_secs(1);
+
+/// Bytes on their way to a peer, or to everyone on a link.
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub to: SocketAddr,
+    pub bytes: Vec<u8>,
+    /// Link to leave through, for multicast.
+    pub via: Option<Link>,
+}
+
+/// The network side of the chat, running on a runtime of its own.
+///
+/// A task per socket receives into the inbox, one task sends whatever is
+/// queued and another repeats our `Enter` for peers that missed it. A ticker wakes the front-end,
+/// online or not, so the engine's timers run even when nothing arrives.
+/// The front-end only ever queues and drains, its frames never wait on I/O.
+///
+/// The runtime lives on a thread of its own, so a `Transport` can be made
+/// and dropped anywhere, within another runtime too.
+pub struct Transport {
+    handle: Handle,
+    /// Ends the runtime thread once the given senders are done.
+    close: Option<oneshot::Sender<Vec<JoinHandle<()>>>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    outgoing: Option<mpsc::UnboundedSender<Datagram>>,
+    beacon: watch::Sender<Vec<Datagram>>,
+    sender: Option<JoinHandle<()>>,
+    /// Senders of stopped sockets, still sending what was queued.
+    flushing: Vec<JoinHandle<()>>,
+    tasks: Vec<JoinHandle<()>>,
+    ticker: Option<JoinHandle<()>>,
+}
+
+impl Transport {
+    pub fn new() -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(WORKERS)
+            .thread_name("udp_chat-net")
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let (close, closed) = oneshot::channel::<Vec<JoinHandle<()>>>();
+        let thread = std::thread::Builder::new()
+            .name("udp_chat-runtime".to_string())
+            .spawn(move || {
+                runtime.block_on(async {
+                    for sender in closed.await.unwrap_or_default() {
+                        sender.await.ok();
+                    }
+                });
+                // Disk work still running finishes on its own.
+                runtime.shutdown_background();
+            })?;
+        Ok(Transport {
+            handle,
+            close: Some(close),
+            thread: Some(thread),
+            outgoing: None,
+            beacon: watch::channel(Vec::new()).0,
+            sender: None,
+            flushing: Vec::new(),
+            tasks: Vec::new(),
+            ticker: None,
+        })
+    }
+
+    /// Starts moving datagrams through `sockets`, in place of any earlier ones.
+    pub fn start(
+        &mut self,
+        sockets: Sockets,
+        postman: Postman<(SocketAddr, Message)>,
+        wake: Wake,
+        broken: Arc<AtomicBool>,
+    ) -> io::Result<()> {
+        self.stop();
+        let _runtime = self.handle.enter();
+        let shared = Arc::new(nonblocking(sockets.shared)?);
+        let own = Arc::new(nonblocking(sockets.own)?);
+        for socket in [shared, Arc::clone(&own)] {
+            self.tasks.push(self.handle.spawn(receive(
+                socket,
+                postman.clone(),
+                wake.clone(),
+                Arc::clone(&broken),
+            )));
+        }
+        let (outgoing, queue) = mpsc::unbounded_channel();
+        self.tasks.push(
+            self.handle
+                .spawn(beat(outgoing.clone(), self.beacon.subscribe())),
+        );
+        self.sender = Some(self.handle.spawn(send(own, queue, broken)));
+        self.outgoing = Some(outgoing);
+        Ok(())
+    }
+
+    /// Closes the sockets once what is queued was sent, within `FLUSH`.
+    /// Returns right away.
+    pub fn stop(&mut self) {
+        self.beacon.send_replace(Vec::new());
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.outgoing = None;
+        if let Some(sender) = self.sender.take() {
+            self.flushing.retain(|flush| !flush.is_finished());
+            self.flushing.push(self.handle.spawn(flush(sender)));
+        }
+    }
+
+    /// Wakes the front-end every `TICK`, or no more without `wake`.
+    pub fn tick(&mut self, wake: Option<Wake>) {
+        if let Some(ticker) = self.ticker.take() {
+            ticker.abort();
+        }
+        self.ticker = wake.map(|wake| {
+            self.handle.spawn(async move {
+                let mut interval = tokio::time::interval(TICK);
+                loop {
+                    interval.tick().await;
+                    wake();
+                }
+            })
+        });
+    }
+
+    /// Queues a datagram. Nothing is sent while offline.
+    pub fn send(&self, datagram: Datagram) {
+        if let Some(outgoing) = &self.outgoing {
+            outgoing.send(datagram).ok();
+        }
+    }
+
+    /// Datagrams sent right away and again every `HEARTBEAT`.
+    pub fn set_beacon(&self, datagrams: Vec<Datagram>) {
+        self.beacon.send_replace(datagrams);
+    }
+
+    /// Runs disk work away from both the front-end and the network tasks.
+    pub fn blocking(&self, work: impl FnOnce() + Send + 'static) {
+        self.handle.spawn_blocking(work);
+    }
+}
+
+impl Drop for Transport {
+    /// Waits for the queued datagrams to leave, `FLUSH` at most.
+    fn drop(&mut self) {
+        self.tick(None);
+        self.stop();
+        if let Some(close) = self.close.take() {
+            close.send(std::mem::take(&mut self.flushing)).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Lets a sender finish what is queued, then stops it.
+async fn flush(sender: JoinHandle<()>) {
+    let abort = sender.abort_handle();
+    if tokio::time::timeout(FLUSH, sender).await.is_err() {
+        abort.abort();
+    }
+}
+
+fn nonblocking(socket: std::net::UdpSocket) -> io::Result<UdpSocket> {
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+async fn receive(
+    socket: Arc<UdpSocket>,
+    postman: Postman<(SocketAddr, Message)>,
+    wake: Wake,
+    broken: Arc<AtomicBool>,
+) {
+    let mut buf = [0; 2048];
+    // Told about once each, they won't get any better.
+    let mut mismatched = HashSet::new();
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((number_of_bytes, src_addr)) => {
+                let addr = network::canonical(src_addr);
+                match Message::from_be_bytes(&buf[..number_of_bytes]) {
+                    Ok(message) => {
+                        info!("{}: {}", addr, message);
+                        if !postman.deliver((addr, message)).await {
+                            return;
+                        }
+                        wake();
+                    }
+                    Err(Rejected::Version(version)) if mismatched.insert(addr) => {
+                        warn!(
+                            "{} speaks protocol v{}, we speak v{}.",
+                            addr,
+                            version,
+                            message::VERSION
+                        );
+                    }
+                    // Stray packets and those we were warned about.
+                    Err(_) => (),
+                }
+            }
+            Err(err) if network::is_network_down(&err) => {
+                warn!("Receive! {}", err);
+                broken.store(true, Ordering::Relaxed);
+                wake();
+                tokio::time::sleep(TICK).await;
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+async fn send(
+    socket: Arc<UdpSocket>,
+    mut queue: mpsc::UnboundedReceiver<Datagram>,
+    broken: Arc<AtomicBool>,
+) {
+    while let Some(datagram) = queue.recv().await {
+        if let Some(link) = &datagram.via {
+            network::select(SockRef::from(&*socket), link).ok();
+        }
+        if let Err(err) = socket.send_to(&datagram.bytes, datagram.to).await {
+            if network::is_network_down(&err) {
+                broken.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Sends the beacon whenever it changes and every `HEARTBEAT` in between.
+async fn beat(
+    outgoing: mpsc::UnboundedSender<Datagram>,
+    mut beacon: watch::Receiver<Vec<Datagram>>,
+) {
+    let mut interval = tokio::time::interval(HEARTBEAT);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            changed = beacon.changed() => match changed {
+                Ok(()) => interval.reset(),
+                Err(_) => return,
+            },
+        }
+        for datagram in beacon.borrow_and_update().iter() {
+            if outgoing.send(datagram.clone()).is_err() {
+                return;
+            }
+        }
+    }
+}
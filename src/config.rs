@@ -0,0 +1,272 @@
+use directories::ProjectDirs;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use udp_chat::chat::{
+    network::{Discovery, Interface},
+    peers::Presence,
+    UdpChat,
+};
+
+pub const DEFAULT_PORT: u16 = 4444;
+const CONFIG_FILE: &str = "config.toml";
+const DB_FILE: &str = "history.db";
+const DOWNLOADS_DIR: &str = "downloads";
+
+pub const USAGE: &str = "Usage: udp_chat [OPTIONS]
+
+Options:
+  -n, --name <NAME>        Nickname shown to peers
+  -p, --port <PORT>        UDP port to use [default: 4444]
+  -i, --interface <IFACE>  Interface name, local IPv4 or IPv6 address, all or auto
+                           [default: auto]
+  -d, --discovery <MODE>   broadcast or multicast, IPv6 always uses multicast
+                           [default: broadcast]
+      --db <PATH>          Path to the history database
+      --profile <NAME>     Separate settings, history and keys, e.g. to run
+                           several instances on one host
+      --tui                Run in the terminal instead of a window
+  -h, --help               Print this help";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub name: String,
+    pub port: u16,
+    pub interface: Interface,
+    pub discovery: Discovery,
+    pub db_path: Option<PathBuf>,
+    /// Rooms to join on start besides the default one.
+    pub channels: Vec<String>,
+    pub presence: Presence,
+    /// Custom status shown next to the name.
+    pub status: String,
+    /// Keeps settings and data apart from other instances. Only set from the command line.
+    #[serde(skip)]
+    pub profile: Option<String>,
+    /// What command-line flags changed. Never saved.
+    #[serde(skip)]
+    pub overrides: Overrides,
+}
+
+/// Values set by command-line flags, each with the saved one it covers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides {
+    name: Option<(String, String)>,
+    port: Option<(u16, u16)>,
+    interface: Option<(Interface, Interface)>,
+    discovery: Option<(Discovery, Discovery)>,
+    db_path: Option<(Option<PathBuf>, Option<PathBuf>)>,
+}
+
+/// Sets `field` to a flag's `value`, keeping what it covers.
+fn set<T: Clone>(field: &mut T, overridden: &mut Option<(T, T)>, value: T) {
+    let saved = match overridden.take() {
+        Some((_, saved)) => saved,
+        None => field.clone(),
+    };
+    *field = value.clone();
+    *overridden = Some((value, saved));
+}
+
+/// What to save for `field`: the covered value while the flag's one is in effect.
+fn saved<T: Clone + PartialEq>(field: &T, overridden: &Option<(T, T)>) -> T {
+    match overridden {
+        Some((value, saved)) if value == field => saved.clone(),
+        _ => field.clone(),
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: String::new(),
+            port: DEFAULT_PORT,
+            interface: Interface::default(),
+            discovery: Discovery::default(),
+            db_path: None,
+            channels: Vec::new(),
+            presence: Presence::default(),
+            status: String::new(),
+            profile: None,
+            overrides: Overrides::default(),
+        }
+    }
+}
+
+/// Directories of the app, or of one of its profiles.
+pub fn project_dirs(profile: Option<&str>) -> Option<ProjectDirs> {
+    let name = match profile {
+        Some(profile) => format!("{}-{}", env!("CARGO_PKG_NAME"), profile),
+        None => env!("CARGO_PKG_NAME").to_string(),
+    };
+    ProjectDirs::from("com", "p4ymak", &name)
+}
+
+impl Config {
+    /// Reads the config file. The flag is `true` when there was nothing to read
+    /// and the user has to go through the first-run settings.
+    pub fn load(profile: Option<String>) -> (Self, bool) {
+        let (config, first_run) = Config::read(Config::path(profile.as_deref()));
+        (Config { profile, ..config }, first_run)
+    }
+
+    fn read(path: Option<PathBuf>) -> (Self, bool) {
+        let path = match path {
+            Some(path) => path,
+            None => return (Config::default(), true),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => match toml::from_str::<Config>(&text) {
+                Ok(config) => {
+                    info!("Config loaded from {}", path.display());
+                    let first_run = config.name.trim().is_empty();
+                    (config, first_run)
+                }
+                Err(err) => {
+                    warn!("Config! {}: {}", path.display(), err);
+                    (Config::default(), true)
+                }
+            },
+            Err(_) => (Config::default(), true),
+        }
+    }
+
+    /// Writes the config file, without what the command line overrides.
+    pub fn save(&self) -> Result<(), String> {
+        let path = Config::path(self.profile.as_deref()).ok_or("No config directory.")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        let text = toml::to_string(&self.saved()).map_err(|err| err.to_string())?;
+        std::fs::write(&path, text).map_err(|err| err.to_string())?;
+        info!("Config saved to {}", path.display());
+        Ok(())
+    }
+
+    /// This config as it was before command-line flags, where they still apply.
+    fn saved(&self) -> Config {
+        let overrides = &self.overrides;
+        Config {
+            name: saved(&self.name, &overrides.name),
+            port: saved(&self.port, &overrides.port),
+            interface: saved(&self.interface, &overrides.interface),
+            discovery: saved(&self.discovery, &overrides.discovery),
+            db_path: saved(&self.db_path, &overrides.db_path),
+            overrides: Overrides::default(),
+            ..self.clone()
+        }
+    }
+
+    pub fn path(profile: Option<&str>) -> Option<PathBuf> {
+        project_dirs(profile).map(|p| p.config_dir().join(CONFIG_FILE))
+    }
+
+    /// Database location, falling back to the data directory.
+    pub fn db_path(&self) -> Option<PathBuf> {
+        match &self.db_path {
+            Some(path) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).ok();
+                }
+                Some(path.to_owned())
+            }
+            None => project_dirs(self.profile.as_deref()).map(|p| {
+                std::fs::create_dir_all(p.data_dir()).ok();
+                p.data_dir().join(DB_FILE)
+            }),
+        }
+    }
+
+    /// Where the identity key and trusted peer keys live.
+    pub fn keys_dir(&self) -> Option<PathBuf> {
+        project_dirs(self.profile.as_deref()).map(|p| p.data_dir().to_path_buf())
+    }
+
+    /// Chat set up from this config, not started yet.
+    pub fn chat(&self) -> io::Result<UdpChat> {
+        let mut chat = UdpChat::new(
+            self.name.to_owned(),
+            self.port,
+            self.interface.to_owned(),
+            self.discovery,
+            self.db_path(),
+            self.keys_dir(),
+            self.downloads_dir(),
+        )?;
+        // Not started yet, so these only fill what gets announced in `prelude`.
+        self.channels.iter().for_each(|channel| {
+            chat.join(channel);
+        });
+        chat.set_presence(self.presence, &self.status);
+        Ok(chat)
+    }
+
+    /// Where received files are saved.
+    pub fn downloads_dir(&self) -> PathBuf {
+        project_dirs(self.profile.as_deref())
+            .map(|p| p.data_dir().join(DOWNLOADS_DIR))
+            .unwrap_or_else(|| PathBuf::from(DOWNLOADS_DIR))
+    }
+
+    /// Overrides fields with command-line flags, for this run only.
+    pub fn apply_args(&mut self, args: impl Iterator<Item = String>) -> Result<(), String> {
+        let overrides = &mut self.overrides;
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", flag))
+            };
+            match arg.as_str() {
+                "-n" | "--name" => set(&mut self.name, &mut overrides.name, value(&arg)?),
+                "-p" | "--port" => {
+                    let port = value(&arg)?
+                        .parse()
+                        .map_err(|err| format!("Bad port: {}", err))?;
+                    set(&mut self.port, &mut overrides.port, port)
+                }
+                "-i" | "--interface" => {
+                    let interface = value(&arg)?.parse()?;
+                    set(&mut self.interface, &mut overrides.interface, interface)
+                }
+                "-d" | "--discovery" => {
+                    let discovery = value(&arg)?.parse()?;
+                    set(&mut self.discovery, &mut overrides.discovery, discovery)
+                }
+                "--db" => {
+                    let db_path = Some(PathBuf::from(value(&arg)?));
+                    set(&mut self.db_path, &mut overrides.db_path, db_path)
+                }
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_without_overrides() {
+        let mut config = Config {
+            name: "saved".to_string(),
+            ..Config::default()
+        };
+        let args = ["--name", "flag", "-p", "5000", "--db", "/tmp/flag.db"];
+        config
+            .apply_args(args.iter().map(|a| a.to_string()))
+            .unwrap();
+        assert_eq!((config.name.as_str(), config.port), ("flag", 5000));
+        let saved = config.saved();
+        assert_eq!(saved.name, "saved");
+        assert_eq!(saved.port, DEFAULT_PORT);
+        assert_eq!(saved.db_path, None);
+        // Changed after start, so no longer the flag's.
+        config.port = 6000;
+        assert_eq!(config.saved().port, 6000);
+    }
+}
@@ -0,0 +1,7 @@
+//! Chat over a local network, without any UI.
+//!
+//! `UdpChat` does the networking, the protocol and the history; front-ends
+//! drive it and follow what happens through `ChatEvent`s.
+pub mod chat;
+
+pub use chat::{event::ChatEvent, HistoryEntry, Recepients, UdpChat, Wake};
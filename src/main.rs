@@ -1,10 +1,69 @@
+// Without a front-end there is nothing to run the config for.
+#![cfg_attr(not(any(feature = "gui", feature = "tui")), allow(dead_code))]
+#[cfg(feature = "gui")]
 mod app;
-mod chat;
-use app::ChatApp;
-use eframe::egui::Vec2;
+mod config;
+#[cfg(feature = "tui")]
+mod tui;
+use config::{Config, USAGE};
 
 fn main() {
-    let start_state = ChatApp::default();
+    env_logger::init();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let terminal = args.iter().any(|a| a == "--tui");
+    args.retain(|a| a != "--tui");
+    let profile = match args.iter().position(|a| a == "--profile") {
+        Some(i) if i + 1 < args.len() => {
+            args.remove(i);
+            Some(args.remove(i))
+        }
+        Some(_) => {
+            eprintln!("Missing value for --profile\n\n{}", USAGE);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    let (mut config, first_run) = Config::load(profile);
+    if let Err(err) = config.apply_args(args.into_iter()) {
+        eprintln!("{}\n\n{}", err, USAGE);
+        std::process::exit(2);
+    }
+    // A name from the command line is enough to start with.
+    let first_run = first_run && config.name.trim().is_empty();
+    if terminal || cfg!(not(feature = "gui")) {
+        run_terminal(config, first_run);
+    } else {
+        run_window(config, first_run);
+    }
+}
+
+#[cfg(feature = "tui")]
+fn run_terminal(config: Config, first_run: bool) {
+    if let Err(err) = tui::run(config, first_run) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_terminal(_config: Config, _first_run: bool) {
+    eprintln!("Built without the terminal client.");
+    std::process::exit(2);
+}
+
+#[cfg(feature = "gui")]
+fn run_window(config: Config, first_run: bool) {
+    let start_state = match app::ChatApp::new(config, first_run) {
+        Ok(app) => app,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
     let options = eframe::NativeOptions {
         always_on_top: false,
         decorated: true,
@@ -13,9 +72,13 @@ fn main() {
         drag_and_drop_support: true,
         transparent: true,
         // icon_data: Some(icon),
-        initial_window_size: Some(Vec2 { x: 350.0, y: 550.0 }),
+        initial_window_size: Some(eframe::egui::Vec2 { x: 350.0, y: 550.0 }),
         ..Default::default()
     };
-    env_logger::init();
     eframe::run_native(Box::new(start_state), options);
 }
+
+#[cfg(not(feature = "gui"))]
+fn run_window(config: Config, first_run: bool) {
+    run_terminal(config, first_run);
+}
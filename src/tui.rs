@@ -0,0 +1,460 @@
+use crate::config::Config;
+use chrono::{Local, TimeZone};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+use udp_chat::chat::{
+    message::{clean_name, Message, DEFAULT_CHANNEL},
+    network::{interfaces, Interface},
+    peers::{NodeId, Presence},
+    transfer::TransferState,
+    HistoryEntry, Recepients,
+};
+use udp_chat::{ChatEvent, UdpChat};
+
+/// How long to wait for a key before looking at the network again.
+const POLL: Duration = Duration::from_millis(20);
+const PEERS_WIDTH: u16 = 24;
+const HELP: &str = "/join <channel>  /leave  /go <#channel|peer>  /send <path>  /accept  /cancel  \
+     /status <online|away|busy> [text]  /interface [name|ip|all|auto]  /retry  /stats  /quit";
+
+/// Terminal front-end, for when there is no window to open.
+struct Tui {
+    chat: UdpChat,
+    config: Config,
+    input: String,
+    /// Room shown when no direct conversation is open.
+    channel: String,
+    /// Peer of the open direct conversation.
+    conversation: Option<NodeId>,
+    /// Lines scrolled up from the bottom.
+    scroll: u16,
+    /// The scrollback was scrolled past its oldest loaded message.
+    at_top: bool,
+    status: String,
+    events: Receiver<ChatEvent>,
+    quit: bool,
+}
+
+pub fn run(mut config: Config, first_run: bool) -> io::Result<()> {
+    if config.name.trim().is_empty() {
+        print!("Nickname: ");
+        io::stdout().flush()?;
+        let mut name = String::new();
+        io::stdin().lock().read_line(&mut name)?;
+        config.name = clean_name(&name);
+        if config.name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Nickname is empty.",
+            ));
+        }
+    }
+    if first_run {
+        if let Err(err) = config.save() {
+            eprintln!("Config not saved: {}", err);
+        }
+    }
+    let mut chat = config.chat()?;
+    let mut tui = Tui {
+        events: chat.subscribe(),
+        chat,
+        config,
+        input: String::new(),
+        channel: DEFAULT_CHANNEL.to_string(),
+        conversation: None,
+        scroll: 0,
+        at_top: false,
+        status: HELP.to_string(),
+        quit: false,
+    };
+    // Keys are polled often enough to pick up whatever arrives.
+    tui.chat.prelude(Arc::new(|| ()));
+    let mut terminal = ratatui::init();
+    let result = tui.run(&mut terminal);
+    ratatui::restore();
+    tui.chat.shutdown();
+    result
+}
+
+impl Tui {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            self.chat.receive();
+            while let Ok(event) = self.events.try_recv() {
+                self.handle_event(event);
+            }
+            let channel = self.channel.to_owned();
+            if self.at_top {
+                self.chat.load_older(self.conversation, &channel);
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(POLL)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.chat.active();
+                        self.handle_key(key.code, key.modifiers);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Notices in the status line for what the scrollback doesn't show.
+    fn handle_event(&mut self, event: ChatEvent) {
+        self.status = match event {
+            ChatEvent::PeerEntered { name, .. } => format!("{} entered.", name),
+            ChatEvent::PeerLeft { node } => format!("{} left.", self.chat.peer_name(&node)),
+            ChatEvent::KeyRefused { node } => {
+                format!(
+                    "! Someone posed as {} with another key!",
+                    self.chat.peer_name(&node)
+                )
+            }
+            ChatEvent::Presence {
+                node,
+                presence,
+                status,
+            } if node != self.chat.node() => {
+                format!("{} is {}. {}", self.chat.peer_name(&node), presence, status)
+            }
+            ChatEvent::Joined { node, channel } if node != self.chat.node() => {
+                format!("{} joined #{}.", self.chat.peer_name(&node), channel)
+            }
+            ChatEvent::Transfer { id, state } => match self.chat.transfers().get(&id) {
+                Some(t) if t.incoming && state == TransferState::Done => {
+                    format!("Saved {}", t.path.display())
+                }
+                Some(t) => format!("{}: {:?}", t.name, state),
+                None => return,
+            },
+            ChatEvent::OfferFailed { path, error } => format!("{}: {}", path.display(), error),
+            _ => return,
+        };
+    }
+
+    fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        match code {
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Enter => {
+                let input = std::mem::take(&mut self.input);
+                self.submit(input.trim());
+            }
+            KeyCode::Tab => self.next_view(),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ => (),
+        }
+    }
+
+    fn submit(&mut self, input: &str) {
+        if input.is_empty() {
+            return;
+        }
+        let (command, argument) = match input.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (input, ""),
+        };
+        match command {
+            "/quit" => self.quit = true,
+            "/retry" => self.chat.reconnect(),
+            "/stats" => self.status = format!("Inbox: {}", self.chat.inbox_stats()),
+            "/join" => match self.chat.join(argument) {
+                Some(channel) => {
+                    self.show(Some(channel), None);
+                    self.save_channels();
+                }
+                None => self.status = format!("Bad channel name '{}'", argument),
+            },
+            "/leave" if self.conversation.is_none() && self.channel != DEFAULT_CHANNEL => {
+                let channel = self.channel.to_owned();
+                self.chat.leave(&channel);
+                self.show(Some(DEFAULT_CHANNEL.to_string()), None);
+                self.save_channels();
+            }
+            "/go" => self.go(argument),
+            "/interface" if argument.is_empty() => {
+                let list: Vec<String> = interfaces()
+                    .iter()
+                    .map(|iface| format!("{} {}", iface.name, iface.ip))
+                    .collect();
+                self.status = format!("On {}: {}", self.chat.interface(), list.join(", "));
+            }
+            "/interface" => match argument.parse::<Interface>() {
+                Ok(interface) => {
+                    self.config.interface = interface.to_owned();
+                    self.chat.set_interface(interface);
+                    if let Err(err) = self.config.save() {
+                        self.status = format!("Interface not saved: {}", err);
+                    }
+                }
+                Err(err) => self.status = err,
+            },
+            "/status" => {
+                let (presence, text) = match argument.split_once(' ') {
+                    Some((presence, text)) => (presence, text.trim()),
+                    None => (argument, ""),
+                };
+                match presence.parse::<Presence>() {
+                    Ok(presence) => {
+                        self.chat.set_presence(presence, text);
+                        self.config.presence = presence;
+                        self.config.status = self.chat.status().to_owned();
+                        self.status = match self.config.save() {
+                            Ok(_) => String::new(),
+                            Err(err) => format!("Status not saved: {}", err),
+                        };
+                    }
+                    Err(err) => self.status = err,
+                }
+            }
+            "/send" => {
+                let addrs = match self.conversation {
+                    Some(ip) => Recepients::One(ip),
+                    None => Recepients::Peers,
+                };
+                let channel = self.channel.to_owned();
+                self.status = match self.chat.send_file(Path::new(argument), addrs, &channel) {
+                    Ok(_) => String::new(),
+                    Err(err) => format!("{}: {}", argument, err),
+                };
+            }
+            "/accept" | "/cancel" => {
+                let files: Vec<u64> = self.view().filter_map(|m| m.file).collect();
+                for id in files {
+                    match (command, self.chat.transfers().get(&id).map(|t| t.state)) {
+                        ("/accept", Some(TransferState::Offered | TransferState::Failed)) => {
+                            self.chat.resume_transfer(id)
+                        }
+                        ("/cancel", Some(TransferState::Running | TransferState::Paused)) => {
+                            self.chat.cancel_transfer(id)
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ if command.starts_with('/') => self.status = HELP.to_string(),
+            _ => {
+                match self.conversation {
+                    Some(ip) => {
+                        self.chat.send(Message::direct(input), Recepients::One(ip));
+                    }
+                    None => {
+                        self.chat.send(
+                            Message::text(input).in_channel(&self.channel),
+                            Recepients::Peers,
+                        );
+                    }
+                }
+                self.scroll = 0;
+            }
+        }
+    }
+
+    /// Opens a room, or a direct conversation with a peer given by name or address.
+    fn go(&mut self, target: &str) {
+        let channel = target.strip_prefix('#').unwrap_or(target);
+        if channel == DEFAULT_CHANNEL || self.chat.channels().iter().any(|c| c == channel) {
+            self.show(Some(channel.to_string()), None);
+            return;
+        }
+        match self.chat.peers().find(target) {
+            Some(node) => self.show(None, Some(node)),
+            None => self.status = format!("No channel or peer '{}'", target),
+        }
+    }
+
+    /// Cycles through rooms and direct conversations.
+    fn next_view(&mut self) {
+        let mut views: Vec<(Option<String>, Option<NodeId>)> =
+            std::iter::once(DEFAULT_CHANNEL.to_string())
+                .chain(self.chat.channels().iter().cloned())
+                .map(|channel| (Some(channel), None))
+                .collect();
+        for &node in self.chat.conversations() {
+            if !views.contains(&(None, Some(node))) {
+                views.push((None, Some(node)));
+            }
+        }
+        let current = match self.conversation {
+            Some(node) => (None, Some(node)),
+            None => (Some(self.channel.to_owned()), None),
+        };
+        let next = views
+            .iter()
+            .position(|view| view == &current)
+            .map_or(0, |i| (i + 1) % views.len());
+        let (channel, conversation) = views.swap_remove(next);
+        self.show(channel, conversation);
+    }
+
+    fn show(&mut self, channel: Option<String>, conversation: Option<NodeId>) {
+        if let Some(channel) = channel {
+            self.channel = channel;
+        }
+        self.conversation = conversation;
+        self.scroll = 0;
+        self.status = String::new();
+    }
+
+    fn save_channels(&mut self) {
+        self.config.channels = self.chat.channels().to_owned();
+        if let Err(err) = self.config.save() {
+            self.status = format!("Channels not saved: {}", err);
+        }
+    }
+
+    fn view(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        let conversation = self.conversation;
+        let channel = &self.channel;
+        self.chat
+            .history()
+            .iter()
+            .filter(move |m| m.is_in(conversation, channel))
+    }
+
+    fn title(&self) -> String {
+        match self.conversation {
+            Some(node) => format!("@{}", self.chat.peer_name(&node)),
+            None => format!("#{}", self.channel),
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [top, body, bottom] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [messages, peers] =
+            Layout::horizontal([Constraint::Min(0), Constraint::Length(PEERS_WIDTH)]).areas(body);
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                "{} {}  {}  {}",
+                self.chat.presence().icon(),
+                self.chat.connection(),
+                self.chat.db_status(),
+                self.status
+            )),
+            top,
+        );
+
+        // Only the newest messages, as far back as the scroll reaches.
+        let width = messages.width.saturating_sub(2).max(1) as usize;
+        let visible = messages.height.saturating_sub(2) as usize;
+        let wanted = visible + self.scroll as usize;
+        let mut shown = Vec::new();
+        let mut height = 0;
+        for m in self.view().rev() {
+            if height >= wanted {
+                break;
+            }
+            let lines = self.lines(m);
+            height += lines
+                .iter()
+                .map(|line| line.width().max(1).div_ceil(width))
+                .sum::<usize>();
+            shown.push(lines);
+        }
+        self.at_top = height < wanted;
+        let lines: Vec<Line> = shown.into_iter().rev().flatten().collect();
+        let bottom_offset = height.saturating_sub(visible);
+        let offset = bottom_offset.saturating_sub(self.scroll as usize);
+        frame.render_widget(
+            Paragraph::new(lines)
+                .wrap(Wrap { trim: false })
+                .scroll((offset as u16, 0))
+                .block(Block::default().borders(Borders::ALL).title(self.title())),
+            messages,
+        );
+
+        let mut roster: Vec<(String, Presence)> = self
+            .chat
+            .peers()
+            .iter()
+            .map(|(_, peer)| match peer.name.is_empty() {
+                true => (peer.addr.to_string(), peer.presence),
+                false => (format!("{} {}", peer.name, peer.addr), peer.presence),
+            })
+            .collect();
+        roster.sort_by(|a, b| a.0.cmp(&b.0));
+        let roster = roster
+            .into_iter()
+            .map(|(name, presence)| format!("{} {}", presence.icon(), name));
+        frame.render_widget(
+            List::new(roster).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("Online: {}", self.chat.peers().len())),
+            ),
+            peers,
+        );
+
+        frame.render_widget(
+            Paragraph::new(self.input.as_str()).block(Block::default().borders(Borders::ALL)),
+            bottom,
+        );
+        let cursor = (self.input.chars().count() as u16 + 1).min(bottom.width.saturating_sub(2));
+        frame.set_cursor_position(Position::new(bottom.x + cursor, bottom.y + 1));
+    }
+
+    /// A history entry as it appears in the scrollback.
+    fn lines(&self, m: &HistoryEntry) -> Vec<Line<'static>> {
+        let time = Local
+            .timestamp_opt(m.timestamp as i64, 0)
+            .single()
+            .map(|time| time.format("%H:%M").to_string())
+            .unwrap_or_default();
+        let name = match m.node == self.chat.node() {
+            true => self.chat.name().to_owned(),
+            false => self
+                .chat
+                .peers()
+                .name(&m.node)
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| m.name.to_owned()),
+        };
+        let mut status = String::new();
+        if let Some(delivery) = m.delivery {
+            status.push(' ');
+            status.push_str(delivery.icon());
+        }
+        if let Some(t) = m.file.and_then(|id| self.chat.transfers().get(&id)) {
+            status.push_str(&match t.state {
+                TransferState::Offered => " [/accept to download]".to_string(),
+                TransferState::Running => format!(" [{:.0}%]", t.progress() * 100.0),
+                TransferState::Paused => " [paused]".to_string(),
+                TransferState::Failed => " [failed, /accept to retry]".to_string(),
+                TransferState::Done => format!(" [{}]", t.path.display()),
+                TransferState::Cancelled => " [cancelled]".to_string(),
+            });
+        }
+        let mut text = m.text.lines().map(|line| line.to_string());
+        let mut lines = vec![Line::from(vec![
+            Span::raw(format!("{} ", time)),
+            Span::styled(name, Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!(": {}", text.next().unwrap_or_default())),
+        ])];
+        lines.extend(text.map(|line| Line::from(format!("  {}", line))));
+        if let Some(last) = lines.last_mut() {
+            last.push_span(Span::raw(status));
+        }
+        lines
+    }
+}